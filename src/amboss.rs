@@ -1,17 +1,20 @@
-use std::time::Duration;
+use std::{panic::AssertUnwindSafe, time::Duration};
 
 use anyhow::{anyhow, Error};
 use chrono::Utc;
 use cln_plugin::Plugin;
 use cln_rpc::{model::requests::SignmessageRequest, ClnRpc};
+use futures::FutureExt;
 use log::{info, warn};
 use reqwest::Client;
 use serde_json::{json, Value};
 use tokio::time::{self, Instant};
 
 use crate::{
-    structs::PluginState,
-    util::{make_rpc_path, send_mail, send_telegram},
+    notify::notify,
+    structs::{Message, PluginState, Severity},
+    templates::Templates,
+    util::{make_rpc_path, panic_message},
 };
 
 async fn amboss_ping(plugin: Plugin<PluginState>) -> Result<(), Error> {
@@ -76,27 +79,37 @@ pub async fn amboss_ping_loop(plugin: Plugin<PluginState>) -> Result<(), Error>
     let mut sleep_time_s = 300;
     loop {
         {
-            match amboss_ping(plugin.clone()).await {
-                Ok(_succ) => sleep_time_s = 300,
+            match AssertUnwindSafe(amboss_ping(plugin.clone()))
+                .catch_unwind()
+                .await
+                .unwrap_or_else(|panic| Err(anyhow!("panicked: {}", panic_message(&panic))))
+            {
+                Ok(_succ) => {
+                    plugin
+                        .state()
+                        .stats
+                        .lock()
+                        .record(|s| s.amboss_pings_ok += 1);
+                    sleep_time_s = 300
+                }
                 Err(e) => {
                     warn!("Error in amboss_ping: {}", e);
+                    plugin
+                        .state()
+                        .stats
+                        .lock()
+                        .record(|s| s.amboss_pings_failed += 1);
 
                     if sleep_time_s >= 300 {
                         sleep_time_s = 10;
                     } else {
-                        let config = plugin.state().config.lock().clone();
-                        let subject = "Amboss error".to_string();
-                        let body = e.to_string();
-                        if config.send_mail {
-                            if let Err(e) = send_mail(&config, &subject, &body, false).await {
-                                warn!("amboss_ping_loop: Error sending mail: {}", e);
-                            };
-                        }
-                        if config.send_telegram {
-                            if let Err(e) = send_telegram(&config, &subject, &body).await {
-                                warn!("amboss_ping_loop: Error sending telegram: {}", e);
-                            };
-                        }
+                        let message = Templates::load(&plugin).await.render(
+                            "amboss-error",
+                            &[("error", e.to_string())],
+                            None,
+                            Message::plain("Amboss error".to_string(), e.to_string()),
+                        );
+                        notify(&plugin, Severity::Warning, message).await;
                         sleep_time_s += 10;
                     }
                 }
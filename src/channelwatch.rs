@@ -1,10 +1,12 @@
-use std::{collections::HashMap, env, time::Duration};
+use std::{collections::HashMap, panic::AssertUnwindSafe, time::Duration};
 
 use anyhow::{anyhow, Error};
+use chrono::Utc;
 use cln_plugin::Plugin;
 use cln_rpc::{
     model::{
         requests::{
+            CloseRequest,
             ConnectRequest,
             DisconnectRequest,
             GetinfoRequest,
@@ -12,74 +14,431 @@ use cln_rpc::{
             ListnodesRequest,
             ListpeerchannelsRequest,
         },
-        responses::{ListchannelsChannels, ListpeerchannelsChannels},
+        responses::{
+            ListchannelsChannels,
+            ListpeerchannelsChannels,
+            ListpeerchannelsChannelsHtlcs,
+            ListpeerchannelsChannelsHtlcsDirection,
+        },
     },
     primitives::{ChannelState, PublicKey, ShortChannelId},
     ClnRpc,
 };
+use futures::FutureExt;
 use log::{debug, info, warn};
+use parking_lot::Mutex;
 use tokio::time::{self, Instant};
 
 use crate::{
-    structs::{Config, PluginState},
-    util::{make_rpc_path, parse_boolean, send_mail, send_telegram},
+    closures::{check_closures, check_stuck_closes},
+    findings::record_findings,
+    funding::check_funding,
+    ignore::is_ignored,
+    liquidity::{check_depleted, check_node_balance},
+    maintenance::maintenance_until,
+    mute::{is_muted, save_acks},
+    notify::{notify, notify_targets, notify_urgent},
+    onchain::check_resolutions,
+    peers::{check_flapping, needs_peer, update_offline},
+    routing::route,
+    structs::{
+        Config,
+        Finding,
+        FindingCode,
+        HtlcKey,
+        IgnoreEntry,
+        Message,
+        PluginState,
+        Severity,
+        Target,
+    },
+    templates::{join, Templates},
+    uptime::update_uptime,
+    util::{is_test_debug, make_rpc_path, panic_message, STARTUP_GRACE},
 };
 
-async fn check_channel(plugin: Plugin<PluginState>) -> Result<(), Error> {
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(3_000);
+/// Findings with the same code on this many channels are reported as one line
+const GROUP_THRESHOLD: usize = 5;
+const GROUP_MAX_LISTED: usize = 10;
+
+#[derive(Clone, Copy, Debug)]
+pub enum CheckScope {
+    /// Regular check of all channels, reports every finding
+    All,
+    /// Check of a single peer's channels, reports only new findings
+    Peer(PublicKey),
+    /// Check of all channels after a new block, reports only new findings
+    Block,
+}
+
+pub async fn check_channel(plugin: Plugin<PluginState>, scope: CheckScope) -> Result<(), Error> {
     let now = Instant::now();
-    info!("check_channel: Starting");
+    let _guard = match scope {
+        CheckScope::Block => match plugin.state().check_lock.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                debug!("check_channel: Another check is running, skipping block check");
+                return Ok(());
+            }
+        },
+        _ => plugin.state().check_lock.lock().await,
+    };
+    if let CheckScope::All = scope {
+        info!("check_channel: Starting");
+    } else {
+        debug!("check_channel: Starting {:?}", scope);
+    }
 
     let rpc_path = make_rpc_path(&plugin);
     let mut rpc = ClnRpc::new(&rpc_path).await?;
 
-    let channels = rpc
-        .call_typed(&ListpeerchannelsRequest {
-            id: None,
-            short_channel_id: None,
-            channel_id: None,
-        })
-        .await?
-        .channels;
-    info!("check_channel: Got state of all local channels");
-
     let config = plugin.state().config.lock().clone();
+    let ignores = plugin.state().ignores.lock().clone();
 
     let get_info = rpc.call_typed(&GetinfoRequest {}).await?;
 
     let current_blockheight = get_info.blockheight;
 
-    let list_nodes = rpc.call_typed(&ListnodesRequest { id: None }).await?.nodes;
-    let alias_map = list_nodes
+    let peer = if let CheckScope::Peer(p) = scope {
+        Some(p)
+    } else {
+        None
+    };
+    let with_gossip = config.watch_gossip && matches!(scope, CheckScope::All);
+
+    let (channels, findings) = collect_findings(
+        &mut rpc,
+        &config,
+        &ignores,
+        peer,
+        with_gossip,
+        get_info.id,
+        current_blockheight,
+        plugin.state(),
+    )
+    .await?;
+    if let Err(e) = check_closures(
+        &plugin,
+        &mut rpc,
+        &config,
+        &ignores,
+        &channels,
+        matches!(scope, CheckScope::All),
+        current_blockheight,
+    )
+    .await
+    {
+        warn!("check_channel: Error checking for closed channels: {}", e);
+    }
+    update_offline(&plugin, &channels, peer).await;
+    update_uptime(&plugin, &channels, peer).await;
+    let mutes = plugin.state().mutes.lock().clone();
+    let mut new_findings = filter_reported(&plugin, scope, findings.clone());
+    new_findings.retain(|f| !is_muted(&mutes, f));
+    let maintenance = maintenance_until(&plugin);
+
+    let mut reconnect_failures = Vec::new();
+    let mut reconnected = Vec::new();
+    let risks = upstream_risks(&channels, &config, &ignores, current_blockheight);
+    let mut peers = new_findings
+        .iter()
+        .filter(|f| f.code.reconnects())
+        .map(|f| f.peer_id)
+        .chain(risks.iter().map(|r| r.peer_id))
+        .collect::<Vec<_>>();
+    peers.sort();
+    peers.dedup();
+    if !peers.is_empty() && maintenance.is_none() {
+        let peer_connected = channels
+            .iter()
+            .map(|channel| (channel.peer_id, channel.peer_connected))
+            .collect::<HashMap<PublicKey, bool>>();
+        reconnected = reconnect_peers(
+            &plugin,
+            &mut rpc,
+            &peers,
+            &peer_connected,
+            &mut reconnect_failures,
+        )
+        .await;
+    }
+
+    let (channels, findings, risks) = if !reconnected.is_empty() {
+        let (channels, findings) = collect_findings(
+            &mut rpc,
+            &config,
+            &ignores,
+            peer,
+            with_gossip,
+            get_info.id,
+            current_blockheight,
+            plugin.state(),
+        )
+        .await?;
+        let risks = upstream_risks(&channels, &config, &ignores, current_blockheight);
+        (channels, findings, risks)
+    } else {
+        (channels, findings, risks)
+    };
+    if !reconnected.is_empty() {
+        let attempted = reconnected.len() as u64;
+        let failed = reconnected
+            .iter()
+            .filter(|p| reconnect_failures.iter().any(|r| r.peer_id == **p))
+            .count() as u64;
+        let fixed = reconnected
+            .iter()
+            .filter(|p| !findings.iter().any(|f| f.peer_id == **p))
+            .count() as u64;
+        plugin.state().stats.lock().record(|s| {
+            s.reconnects_attempted += attempted;
+            s.reconnects_failed += failed;
+            s.reconnects_fixed += fixed;
+        });
+    }
+    if record_findings(&plugin, scope, &findings) {
+        save_acks(&plugin).await;
+    }
+    // Deadlines don't wait for maintenance windows, mutes or acknowledgements
+    protect_upstream_htlcs(
+        &plugin,
+        &mut rpc,
+        &config,
+        &channels,
+        &risks,
+        current_blockheight,
+    )
+    .await;
+    let findings = filter_reported(&plugin, scope, findings)
         .into_iter()
-        .filter_map(|a| a.alias.map(|alias| (a.nodeid, alias)))
-        .collect::<HashMap<PublicKey, String>>();
+        .filter(|f| {
+            let acknowledged = plugin
+                .state()
+                .findings
+                .lock()
+                .get(&f.key())
+                .is_some_and(|r| r.acknowledged);
+            !acknowledged && !is_muted(&mutes, f)
+        })
+        .collect::<Vec<_>>();
+
+    if findings.is_empty() {
+        if let CheckScope::All = scope {
+            info!(
+                "check_channel: All good. Duration: {}s",
+                now.elapsed().as_secs()
+            );
+        } else {
+            debug!(
+                "check_channel: {:?} all good. Duration: {}s",
+                scope,
+                now.elapsed().as_secs()
+            );
+        }
+        return Ok(());
+    }
+
+    if let Some(until) = maintenance {
+        info!(
+            "check_channel: Maintenance until {}, recorded {} findings without \
+            reconnecting or notifying",
+            until,
+            findings.len()
+        );
+        return Ok(());
+    }
+
+    let report_findings = findings
+        .iter()
+        .chain(
+            reconnect_failures
+                .iter()
+                .filter(|r| findings.iter().any(|f| f.peer_id == r.peer_id)),
+        )
+        .collect::<Vec<_>>();
+    let mut routed: Vec<(Vec<Target>, Vec<&Finding>)> = Vec::new();
+    for finding in report_findings {
+        let targets = route(
+            &config,
+            finding.code.severity(),
+            Some((finding.code, finding.peer_id)),
+        );
+        match routed.iter_mut().find(|(t, _)| *t == targets) {
+            Some((_, routed_findings)) => routed_findings.push(finding),
+            None => routed.push((targets, vec![finding])),
+        }
+    }
 
-    let gossip = if config.watch_gossip {
-        Some(get_gossip_map(&mut rpc, get_info.id).await?)
+    let templates = Templates::load(&plugin).await;
+    info!(
+        "check_channel: Sending notifications. Duration: {}s",
+        now.elapsed().as_secs()
+    );
+    for (targets, routed_findings) in routed {
+        let (severity, message) =
+            channel_report(&plugin, &mut rpc, &templates, &routed_findings).await;
+        notify_targets(&plugin, severity, targets, message).await;
+    }
+
+    let mut reported = plugin.state().reported.lock();
+    reported.retain(|_, at| at.elapsed() < RECONNECT_INTERVAL);
+    for finding in &findings {
+        reported.insert(finding.key(), Instant::now());
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn collect_findings(
+    rpc: &mut ClnRpc,
+    config: &Config,
+    ignores: &[IgnoreEntry],
+    peer: Option<PublicKey>,
+    with_gossip: bool,
+    my_pubkey: PublicKey,
+    current_blockheight: u32,
+    state: &PluginState,
+) -> Result<(Vec<ListpeerchannelsChannels>, Vec<Finding>), Error> {
+    let channels = rpc
+        .call_typed(&ListpeerchannelsRequest {
+            id: peer,
+            short_channel_id: None,
+            channel_id: None,
+        })
+        .await?
+        .channels;
+    debug!(
+        "check_channel: Got state of {} local channels",
+        channels.len()
+    );
+
+    let gossip = if with_gossip {
+        Some(get_gossip_map(rpc, my_pubkey).await?)
     } else {
         None
     };
-    let mut peer_slackers: HashMap<PublicKey, Vec<String>> = HashMap::new();
+    let mut findings = Vec::new();
 
     check_slackers(
         &channels,
-        &config,
-        &mut peer_slackers,
+        config,
+        &mut findings,
         current_blockheight,
         &gossip,
     )?;
+    if config.watch_channels {
+        check_stuck_closes(
+            rpc,
+            config,
+            &channels,
+            &state.state_since,
+            &mut findings,
+            current_blockheight,
+        )
+        .await?;
+        check_funding(
+            rpc,
+            config,
+            &channels,
+            &state.state_since,
+            &mut findings,
+            current_blockheight,
+        )
+        .await?;
+        if config.stuck_htlc_after > 0 {
+            check_stuck_htlcs(
+                &channels,
+                peer.is_none(),
+                config,
+                &state.htlcs,
+                &mut findings,
+            );
+        }
+    }
+    check_resolutions(
+        &channels,
+        &state.resolutions,
+        &mut findings,
+        current_blockheight,
+    );
+    if config.depleted_after > 0 {
+        check_depleted(&channels, config, &state.depletions, &mut findings);
+    }
+    if config.flap_limit > 0 {
+        check_flapping(&channels, config, &state.disconnects, &mut findings);
+    }
+    // The balance of the node needs all channels
+    if config.node_min_ratio > 0 && peer.is_none() {
+        check_node_balance(&channels, config, ignores, my_pubkey, &mut findings);
+    }
+    findings.retain(|f| {
+        let ignored = is_ignored(config, ignores, &f.peer_id, f.scid);
+        if ignored {
+            debug!(
+                "check_channel: Ignoring finding for {}: {}",
+                f.peer_id, f.message
+            );
+        }
+        !ignored
+    });
 
-    let peer_map = channels
+    Ok((channels, findings))
+}
+
+/// Regular checks report everything, event driven checks only what
+/// has not been reported recently
+fn filter_reported(
+    plugin: &Plugin<PluginState>,
+    scope: CheckScope,
+    findings: Vec<Finding>,
+) -> Vec<Finding> {
+    if let CheckScope::All = scope {
+        return findings;
+    }
+    let reported = plugin.state().reported.lock();
+    findings
         .into_iter()
-        .map(|channel| (channel.peer_id, channel))
-        .collect::<HashMap<PublicKey, ListpeerchannelsChannels>>();
-    for (peer, status) in peer_slackers.iter_mut() {
-        let connected = if let Some(p) = peer_map.get(peer) {
-            p.peer_connected
-        } else {
+        .filter(|f| {
+            reported
+                .get(&f.key())
+                .is_none_or(|at| at.elapsed() >= RECONNECT_INTERVAL)
+        })
+        .collect()
+}
+
+/// Disconnect and reconnect `peers` in hope of fixing their channels. Peers we
+/// already reconnected to recently are skipped. Returns the peers that were reconnected.
+async fn reconnect_peers(
+    plugin: &Plugin<PluginState>,
+    rpc: &mut ClnRpc,
+    peers: &[PublicKey],
+    peer_connected: &HashMap<PublicKey, bool>,
+    reconnect_failures: &mut Vec<Finding>,
+) -> Vec<PublicKey> {
+    let peers = {
+        let mut last_reconnect = plugin.state().last_reconnect.lock();
+        last_reconnect.retain(|_, at| at.elapsed() < RECONNECT_INTERVAL);
+        let peers = peers
+            .iter()
+            .filter(|p| !last_reconnect.contains_key(p))
+            .copied()
+            .collect::<Vec<_>>();
+        for peer in &peers {
+            last_reconnect.insert(*peer, Instant::now());
+        }
+        peers
+    };
+    if peers.is_empty() {
+        return peers;
+    }
+
+    for peer in &peers {
+        let Some(connected) = peer_connected.get(peer) else {
             continue;
         };
-        if connected {
+        if *connected {
             info!("check_channel: disconnecting from: {}", peer);
             match rpc
                 .call_typed(&DisconnectRequest {
@@ -96,7 +455,13 @@ async fn check_channel(plugin: Plugin<PluginState>) -> Result<(), Error> {
                         "check_channel: Could not disconnect from {}: {}",
                         peer, de.message
                     );
-                    status.push(format!("Could not disconnect: {}", de.message));
+                    reconnect_failures.push(Finding {
+                        code: FindingCode::ReconnectFailed,
+                        peer_id: *peer,
+                        scid: None,
+                        message: format!("Could not disconnect: {}", de.message),
+                        vars: vec![("error", de.message.clone())],
+                    });
                 }
             };
         } else {
@@ -104,12 +469,10 @@ async fn check_channel(plugin: Plugin<PluginState>) -> Result<(), Error> {
         }
     }
 
-    if !peer_slackers.is_empty() {
-        info!("check_channel: Waiting 10s");
-        time::sleep(Duration::from_secs(10)).await;
-    }
+    info!("check_channel: Waiting 10s");
+    time::sleep(Duration::from_secs(10)).await;
 
-    for (peer, status) in peer_slackers.iter_mut() {
+    for peer in &peers {
         match rpc
             .call_typed(&ConnectRequest {
                 id: peer.to_string(),
@@ -126,83 +489,299 @@ async fn check_channel(plugin: Plugin<PluginState>) -> Result<(), Error> {
                     "check_channel: Could not connect to {}: {}",
                     peer, ce.message
                 );
-                status.push(format!("Could not connect: {}", ce.message));
+                reconnect_failures.push(Finding {
+                    code: FindingCode::ReconnectFailed,
+                    peer_id: *peer,
+                    scid: None,
+                    message: format!("Could not connect: {}", ce.message),
+                    vars: vec![("error", ce.message.clone())],
+                });
             }
         }
     }
 
-    if !peer_slackers.is_empty() {
-        info!("check_channel: Waiting 30s");
-        time::sleep(Duration::from_secs(30)).await;
-    }
+    info!("check_channel: Waiting 30s");
+    time::sleep(Duration::from_secs(30)).await;
+    peers
+}
 
-    let channels = rpc
-        .call_typed(&ListpeerchannelsRequest {
-            id: None,
-            short_channel_id: None,
-            channel_id: None,
-        })
-        .await?
-        .channels;
-    let gossip = if config.watch_gossip {
-        Some(get_gossip_map(&mut rpc, get_info.id).await?)
+/// Direction, deadline and alert threshold of an htlc. An incoming htlc must be
+/// resolved before it expires. An outgoing htlc must be resolved before the
+/// matching upstream htlc expires, which is our `cltv_expiry_delta` later.
+fn htlc_deadline(
+    chan: &ListpeerchannelsChannels,
+    htlc: &ListpeerchannelsChannelsHtlcs,
+    config: &Config,
+) -> (&'static str, u32, u32) {
+    if htlc.direction == ListpeerchannelsChannelsHtlcsDirection::IN {
+        return ("incoming", htlc.expiry, config.expiring_htlcs);
+    }
+    let cltv_delta = chan
+        .updates
+        .as_ref()
+        .map(|u| u.local.cltv_expiry_delta)
+        .unwrap_or_default();
+    let threshold = if config.expiring_htlcs_out > 0 {
+        config.expiring_htlcs_out
     } else {
-        None
+        config.expiring_htlcs
     };
-    let mut peer_slackers: HashMap<PublicKey, Vec<String>> = HashMap::new();
+    ("outgoing", htlc.expiry + cltv_delta, threshold)
+}
 
-    check_slackers(
-        &channels,
-        &config,
-        &mut peer_slackers,
-        current_blockheight,
-        &gossip,
-    )?;
+/// An outgoing htlc that expires in `vitality-htlc-force-close` blocks or less,
+/// the closest one per channel
+struct UpstreamRisk {
+    peer_id: PublicKey,
+    scid: ShortChannelId,
+    blocks_left: u32,
+    deadline: u32,
+    amount_msat: u64,
+}
+impl UpstreamRisk {
+    fn message(&self) -> String {
+        format!(
+            "Outgoing htlc of {} sats in channel {} with {} expires in {} blocks at block {}",
+            self.amount_msat / 1_000,
+            self.scid,
+            self.peer_id,
+            self.blocks_left,
+            self.deadline
+        )
+    }
+}
+
+fn upstream_risks(
+    channels: &[ListpeerchannelsChannels],
+    config: &Config,
+    ignores: &[IgnoreEntry],
+    current_blockheight: u32,
+) -> Vec<UpstreamRisk> {
+    if config.htlc_force_close == 0 {
+        return Vec::new();
+    }
+    let mut risks = Vec::new();
+    for chan in channels {
+        let Some(scid) = chan.short_channel_id else {
+            continue;
+        };
+        if !needs_peer(chan) || is_ignored(config, ignores, &chan.peer_id, Some(scid)) {
+            continue;
+        }
+        let closest = chan
+            .htlcs
+            .iter()
+            .flatten()
+            .filter(|h| h.direction == ListpeerchannelsChannelsHtlcsDirection::OUT)
+            .map(|h| (h.expiry, h.amount_msat.msat()))
+            .min();
+        if let Some((deadline, amount_msat)) = closest {
+            let blocks_left = deadline.saturating_sub(current_blockheight);
+            if blocks_left <= config.htlc_force_close {
+                risks.push(UpstreamRisk {
+                    peer_id: chan.peer_id,
+                    scid,
+                    blocks_left,
+                    deadline,
+                    amount_msat,
+                });
+            }
+        }
+    }
+    risks
+}
 
-    if !peer_slackers.is_empty() {
-        let final_peer_slackers: Vec<String> = peer_slackers
-            .into_iter()
-            .map(|(p, s)| {
-                let concatenated_string = s.join("\n");
-                if let Some(alias) = alias_map.get(&p) {
-                    format!("{} ({}):\n{}\n", p, alias, concatenated_string)
-                } else {
-                    format!("{}:\n{}\n", p, concatenated_string)
+/// Last steps of the protective actions for expiring htlcs. After the reconnect
+/// a channel at `risks` gets a critical notification first. If the htlc is still
+/// pending a block later, the channel is force-closed so the htlc can be timed out
+/// onchain while there is still time to fail the matching upstream htlc.
+async fn protect_upstream_htlcs(
+    plugin: &Plugin<PluginState>,
+    rpc: &mut ClnRpc,
+    config: &Config,
+    channels: &[ListpeerchannelsChannels],
+    risks: &[UpstreamRisk],
+    current_blockheight: u32,
+) {
+    plugin
+        .state()
+        .force_close_warnings
+        .lock()
+        .retain(|scid, _| {
+            risks.iter().any(|r| r.scid == *scid)
+                || !channels.iter().any(|c| c.short_channel_id == Some(*scid))
+        });
+    for risk in risks {
+        let warned_at = plugin
+            .state()
+            .force_close_warnings
+            .lock()
+            .get(&risk.scid)
+            .copied();
+        let (action, subject, body) = match warned_at {
+            None => {
+                warn!(
+                    "check_channel: Will force-close channel {} at the next block: {}",
+                    risk.scid,
+                    risk.message()
+                );
+                plugin
+                    .state()
+                    .force_close_warnings
+                    .lock()
+                    .insert(risk.scid, current_blockheight);
+                (
+                    "warning",
+                    format!("Force-closing channel {} soon", risk.scid),
+                    format!(
+                        "{}. The channel is force-closed at the next block if the htlc is \
+                        still pending then, to protect the upstream htlc.",
+                        risk.message()
+                    ),
+                )
+            }
+            // Give the peer until the next block to resolve the htlc
+            Some(at) if at >= current_blockheight => continue,
+            Some(_) => {
+                warn!(
+                    "check_channel: Force-closing channel {} to protect the upstream htlc: {}",
+                    risk.scid,
+                    risk.message()
+                );
+                let result = rpc
+                    .call_typed(&CloseRequest {
+                        id: risk.scid.to_string(),
+                        unilateraltimeout: Some(1),
+                        destination: None,
+                        fee_negotiation_step: None,
+                        wrong_funding: None,
+                        force_lease_closed: None,
+                        feerange: None,
+                    })
+                    .await;
+                match result {
+                    Ok(_) => (
+                        "closed",
+                        format!("Force-closed channel {}", risk.scid),
+                        format!(
+                            "Force-closed channel {} to protect the upstream htlc. {}",
+                            risk.scid,
+                            risk.message()
+                        ),
+                    ),
+                    Err(e) => (
+                        "failed",
+                        format!("Failed to force-close channel {}", risk.scid),
+                        format!(
+                            "Could not force-close channel {} to protect the upstream htlc: \
+                            {}. {}",
+                            risk.scid,
+                            e,
+                            risk.message()
+                        ),
+                    ),
                 }
-            })
-            .collect();
-        info!(
-            "check_channel: Sending notifications. Duration: {}s",
-            now.elapsed().as_secs()
+            }
+        };
+        let message = Templates::load(plugin).await.render(
+            "htlc-force-close",
+            &[
+                ("action", action.to_string()),
+                ("peer_id", risk.peer_id.to_string()),
+                ("scid", risk.scid.to_string()),
+                ("blocks_left", risk.blocks_left.to_string()),
+                ("deadline", risk.deadline.to_string()),
+                ("message", risk.message()),
+            ],
+            None,
+            Message::plain(subject, body),
+        );
+        let targets = route(
+            config,
+            Severity::Critical,
+            Some((FindingCode::ExpiringHtlc, risk.peer_id)),
         );
-        let subject = "Channel check report\n".to_string();
-        let body = final_peer_slackers.join("\n");
-        if config.send_mail {
-            send_mail(&config, &subject, &body, false).await?;
+        notify_urgent(plugin, targets, message).await;
+    }
+}
+
+/// Remember when we first saw each htlc and flag channels with htlcs that are
+/// pending for longer than `vitality-stuck-htlc-after`. With `all_channels` the
+/// htlcs of channels that are gone are forgotten too.
+fn check_stuck_htlcs(
+    channels: &[ListpeerchannelsChannels],
+    all_channels: bool,
+    config: &Config,
+    htlcs: &Mutex<HashMap<HtlcKey, i64>>,
+    findings: &mut Vec<Finding>,
+) {
+    let now = Utc::now().timestamp();
+    let mut htlcs = htlcs.lock();
+    if all_channels {
+        htlcs.retain(|key, _| channels.iter().any(|c| c.short_channel_id == Some(key.0)));
+    }
+    for chan in channels {
+        let Some(scid) = chan.short_channel_id else {
+            continue;
+        };
+        let pending = chan
+            .htlcs
+            .iter()
+            .flatten()
+            .map(|h| {
+                let incoming = h.direction == ListpeerchannelsChannelsHtlcsDirection::IN;
+                ((scid, incoming, h.id), h)
+            })
+            .collect::<Vec<_>>();
+        htlcs.retain(|key, _| key.0 != scid || pending.iter().any(|(k, _)| k == key));
+
+        let mut stuck = Vec::new();
+        for (key, htlc) in pending {
+            let age = now - *htlcs.entry(key).or_insert(now);
+            if age >= config.stuck_htlc_after as i64 {
+                let direction = if key.1 { "incoming" } else { "outgoing" };
+                stuck.push(format!(
+                    "{} {} sats for {}m",
+                    direction,
+                    htlc.amount_msat.msat() / 1_000,
+                    age / 60
+                ));
+            }
         }
-        if config.send_telegram {
-            send_telegram(&config, &subject, &body).await?;
+        if stuck.is_empty() {
+            continue;
         }
-    } else {
-        info!(
-            "check_channel: All good. Duration: {}s",
-            now.elapsed().as_secs()
+        warn!(
+            "check_channel: Found peer {} with channel {} with stuck htlcs: {}",
+            chan.peer_id,
+            scid,
+            stuck.join(", ")
+        );
+        add_finding(
+            findings,
+            FindingCode::StuckHtlc,
+            chan,
+            format!(
+                "Found channel {} with {} stuck htlcs: {}",
+                scid,
+                stuck.len(),
+                stuck.join(", ")
+            ),
+            vec![("htlcs", stuck.join(", "))],
         );
     }
-
-    Ok(())
 }
 
 fn check_slackers(
     channels: &Vec<ListpeerchannelsChannels>,
     config: &Config,
-    peer_slackers: &mut HashMap<PublicKey, Vec<String>>,
+    findings: &mut Vec<Finding>,
     current_blockheight: u32,
     gossip: &Option<HashMap<ShortChannelId, Vec<ListchannelsChannels>>>,
 ) -> Result<(), anyhow::Error> {
     for chan in channels {
         match chan.state {
-            ChannelState::CHANNELD_AWAITING_LOCKIN => {
+            ChannelState::CHANNELD_AWAITING_LOCKIN | ChannelState::DUALOPEND_AWAITING_LOCKIN => {
                 if config.watch_channels {
                     let statuses = chan
                         .status
@@ -214,10 +793,12 @@ fn check_slackers(
                                 "check_channel: Peer won't lockin our channel: {} status: {}",
                                 chan.peer_id, status
                             );
-                            update_slackers(
-                                peer_slackers,
-                                chan.peer_id,
+                            add_finding(
+                                findings,
+                                FindingCode::NoLockin,
+                                chan,
                                 format!("Peer won't lockin our channel. Status: {}", status),
+                                vec![("status", status.clone())],
                             );
                         }
                         if status.contains("Sent reestablish, waiting for theirs") {
@@ -225,10 +806,12 @@ fn check_slackers(
                                 "check_channel: Peer won't reestablish our channel: {} status: {}",
                                 chan.peer_id, status
                             );
-                            update_slackers(
-                                peer_slackers,
-                                chan.peer_id,
+                            add_finding(
+                                findings,
+                                FindingCode::NoReestablish,
+                                chan,
                                 format!("Peer won't reestablish our channel. Status: {}", status),
+                                vec![("status", status.clone())],
                             );
                         }
                     }
@@ -236,24 +819,26 @@ fn check_slackers(
             }
             ChannelState::CHANNELD_NORMAL | ChannelState::CHANNELD_AWAITING_SPLICE => {
                 if config.watch_channels {
-                    let statuses = chan.status.as_ref().unwrap();
+                    let statuses = chan.status.clone().unwrap_or_default();
                     let mut contained_reconnect = false;
                     let mut specific_error_found = false;
-                    for status in statuses {
+                    for status in &statuses {
                         if status.to_lowercase().contains("error") {
                             warn!(
                                 "check_channel: Found peer with error in status but not \
                                 in closing state: {} status: {}",
                                 chan.peer_id, status
                             );
-                            update_slackers(
-                                peer_slackers,
-                                chan.peer_id,
+                            add_finding(
+                                findings,
+                                FindingCode::StatusError,
+                                chan,
                                 format!(
                                     "Found peer with error in status but not \
                                 in closing state. Status: {}",
                                     status
                                 ),
+                                vec![("status", status.clone())],
                             );
                             specific_error_found = true;
                         }
@@ -262,19 +847,23 @@ fn check_slackers(
                                 "check_channel: Can't agree on fee with: {} status: {}",
                                 chan.peer_id, status
                             );
-                            update_slackers(
-                                peer_slackers,
-                                chan.peer_id,
+                            add_finding(
+                                findings,
+                                FindingCode::UpdateFee,
+                                chan,
                                 format!("Can't agree on fee. Status: {}", status),
+                                vec![("status", status.clone())],
                             );
                             specific_error_found = true;
                         }
                         if status.to_lowercase().contains("htlc") {
                             warn!("check_channel: {} status: {}", chan.peer_id, status);
-                            update_slackers(
-                                peer_slackers,
-                                chan.peer_id,
+                            add_finding(
+                                findings,
+                                FindingCode::HtlcStatus,
+                                chan,
                                 format!("Status: {}", status),
+                                vec![("status", status.clone())],
                             );
                             specific_error_found = true;
                         }
@@ -289,12 +878,14 @@ fn check_slackers(
                                 we are fallen behind i.e. lost some channel state",
                                 chan.peer_id
                             );
-                            update_slackers(
-                                peer_slackers,
-                                chan.peer_id,
+                            add_finding(
+                                findings,
+                                FindingCode::LostState,
+                                chan,
                                 ("Lost state. Status: we are fallen behind \
                                 i.e. lost some channel state")
                                     .to_string(),
+                                Vec::new(),
                             );
                             specific_error_found = true;
                         }
@@ -310,72 +901,84 @@ fn check_slackers(
                             chan.peer_id,
                             statuses.join("\n")
                         );
-                        update_slackers(
-                            peer_slackers,
-                            chan.peer_id,
+                        add_finding(
+                            findings,
+                            FindingCode::NoReconnect,
+                            chan,
                             format!(
                                 "Found disconnected peer that does not want to \
                             reconnect. Status instead is: {}",
                                 statuses.join("\n")
                             ),
+                            vec![("status", statuses.join("\n"))],
                         );
                     }
                 }
+                let Some(scid) = chan.short_channel_id else {
+                    debug!(
+                        "check_channel: Skipping channel without short_channel_id with peer {}",
+                        chan.peer_id
+                    );
+                    continue;
+                };
                 if config.expiring_htlcs > 0 {
-                    let htlcs = chan.htlcs.as_ref().unwrap();
+                    let htlcs = chan.htlcs.as_deref().unwrap_or_default();
                     for htlc in htlcs {
-                        if htlc.expiry - current_blockheight < config.expiring_htlcs {
+                        let (direction, deadline, threshold) = htlc_deadline(chan, htlc, config);
+                        let blocks_left = deadline.saturating_sub(current_blockheight);
+                        if blocks_left < threshold {
                             warn!(
                                 "check_channel: Found peer {} with channel {} with close \
-                                    to expiry htlc: {} blocks",
-                                chan.peer_id,
-                                chan.short_channel_id.unwrap(),
-                                htlc.expiry - current_blockheight
+                                    to expiry {} htlc: {} blocks",
+                                chan.peer_id, scid, direction, blocks_left
                             );
-                            update_slackers(
-                                peer_slackers,
-                                chan.peer_id,
+                            add_finding(
+                                findings,
+                                FindingCode::ExpiringHtlc,
+                                chan,
                                 format!(
-                                    "Found channel {} with close to expiry htlc: {} blocks",
-                                    chan.short_channel_id.unwrap(),
-                                    htlc.expiry - current_blockheight
+                                    "Found channel {} with close to expiry {} htlc of {} sats: \
+                                    {} blocks left until block {}",
+                                    scid,
+                                    direction,
+                                    htlc.amount_msat.msat() / 1_000,
+                                    blocks_left,
+                                    deadline
                                 ),
+                                vec![
+                                    ("blocks_left", blocks_left.to_string()),
+                                    ("direction", direction.to_string()),
+                                    ("deadline", deadline.to_string()),
+                                ],
                             );
                         }
                     }
                 }
                 if let Some(goss) = &gossip {
-                    let public = !chan.private.unwrap();
+                    let public = !chan.private.unwrap_or(true);
                     if !chan.peer_connected {
                         continue;
                     }
-                    if goss.len()
-                        < channels
-                            .iter()
-                            .filter(|s| s.private.is_some() && !s.private.unwrap())
-                            .count()
-                            / 2
+                    if goss.len() < channels.iter().filter(|s| s.private == Some(false)).count() / 2
                     {
                         warn!("check_channel: gossip_store still too empty...");
                         continue;
                     }
-                    let chan_goss = goss.get(&chan.short_channel_id.unwrap());
+                    let chan_goss = goss.get(&scid);
 
                     if let Some(chan_gossip) = chan_goss {
                         if chan_gossip.len() == 1 {
                             warn!(
                                 "check_channel: Found connected peer {} with channel {} \
                                     with one-sided gossip",
-                                chan.peer_id,
-                                chan.short_channel_id.unwrap()
+                                chan.peer_id, scid
                             );
-                            update_slackers(
-                                peer_slackers,
-                                chan.peer_id,
-                                format!(
-                                    "Found connected channel {} with one-sided gossip",
-                                    chan.short_channel_id.unwrap()
-                                ),
+                            add_finding(
+                                findings,
+                                FindingCode::OneSidedGossip,
+                                chan,
+                                format!("Found connected channel {} with one-sided gossip", scid),
+                                Vec::new(),
                             );
                         } else {
                             for side in chan_gossip {
@@ -383,32 +986,34 @@ fn check_slackers(
                                     warn!(
                                         "check_channel: Found connected peer {} with channel {} \
                                         with inactive gossip",
-                                        chan.peer_id,
-                                        chan.short_channel_id.unwrap()
+                                        chan.peer_id, scid
                                     );
-                                    update_slackers(
-                                        peer_slackers,
-                                        chan.peer_id,
+                                    add_finding(
+                                        findings,
+                                        FindingCode::InactiveGossip,
+                                        chan,
                                         format!(
                                             "Found connected channel {} with inactive gossip",
-                                            chan.short_channel_id.unwrap()
+                                            scid
                                         ),
+                                        Vec::new(),
                                     );
                                 }
                                 if public && !side.public {
                                     warn!(
                                         "check_channel: Found public peer {} with channel {} \
                                         with non-public gossip",
-                                        chan.peer_id,
-                                        chan.short_channel_id.unwrap()
+                                        chan.peer_id, scid
                                     );
-                                    update_slackers(
-                                        peer_slackers,
-                                        chan.peer_id,
+                                    add_finding(
+                                        findings,
+                                        FindingCode::NonPublicGossip,
+                                        chan,
                                         format!(
                                             "Found public channel {} with non-public gossip",
-                                            chan.short_channel_id.unwrap()
+                                            scid
                                         ),
+                                        Vec::new(),
                                     );
                                 }
                             }
@@ -416,16 +1021,14 @@ fn check_slackers(
                     } else {
                         warn!(
                             "check_channel: Found peer {} with channel {} with no gossip",
-                            chan.peer_id,
-                            chan.short_channel_id.unwrap()
+                            chan.peer_id, scid
                         );
-                        update_slackers(
-                            peer_slackers,
-                            chan.peer_id,
-                            format!(
-                                "Found channel {} with no gossip",
-                                chan.short_channel_id.unwrap()
-                            ),
+                        add_finding(
+                            findings,
+                            FindingCode::NoGossip,
+                            chan,
+                            format!("Found channel {} with no gossip", scid),
+                            Vec::new(),
                         );
                     }
                 }
@@ -481,50 +1084,198 @@ async fn get_gossip_map(
     Ok(map)
 }
 
-fn update_slackers(
-    peer_slackers: &mut HashMap<PublicKey, Vec<String>>,
-    peer_id: PublicKey,
-    status: String,
-) {
-    if let Some(slack) = peer_slackers.get_mut(&peer_id) {
-        slack.push(status)
-    } else {
-        peer_slackers.insert(peer_id, vec![status]);
+/// Collapse findings with the same code on at least `GROUP_THRESHOLD` channels
+/// into one line, e.g. after a gossip_store rebuild. Returns the grouped lines and
+/// the remaining findings.
+fn group_findings<'a>(findings: &[&'a Finding]) -> (Vec<String>, Vec<&'a Finding>) {
+    let mut codes = Vec::new();
+    let mut by_code: HashMap<FindingCode, Vec<&Finding>> = HashMap::new();
+    for finding in findings {
+        if !by_code.contains_key(&finding.code) {
+            codes.push(finding.code);
+        }
+        by_code.entry(finding.code).or_default().push(finding);
+    }
+
+    let mut groups = Vec::new();
+    for code in codes {
+        let group = &by_code[&code];
+        if group.len() < GROUP_THRESHOLD {
+            continue;
+        }
+        let kind = if group.iter().all(|f| f.scid.is_some()) {
+            "channels"
+        } else {
+            "peers"
+        };
+        let mut listed = group
+            .iter()
+            .take(GROUP_MAX_LISTED)
+            .map(|f| match f.scid {
+                Some(scid) => scid.to_string(),
+                None => f.peer_id.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        if group.len() > GROUP_MAX_LISTED {
+            listed.push_str(&format!(" and {} more", group.len() - GROUP_MAX_LISTED));
+        }
+        groups.push(format!(
+            "{} {} with {}:\n{}",
+            group.len(),
+            kind,
+            code.description(),
+            listed
+        ));
     }
+    let single = findings
+        .iter()
+        .filter(|f| by_code[&f.code].len() < GROUP_THRESHOLD)
+        .copied()
+        .collect();
+    (groups, single)
 }
 
-pub async fn check_channels_loop(plugin: Plugin<PluginState>) -> Result<(), Error> {
-    let mut skip_sleep = false;
-    if let Ok(dbg) = env::var("TEST_DEBUG") {
-        if let Some(bl) = parse_boolean(&dbg) {
-            if bl {
-                skip_sleep = true
-            }
+/// The channel check report for `findings`, grouped by code or by peer
+async fn channel_report(
+    plugin: &Plugin<PluginState>,
+    rpc: &mut ClnRpc,
+    templates: &Templates,
+    findings: &[&Finding],
+) -> (Severity, Message) {
+    let (groups, single_findings) = group_findings(findings);
+
+    let mut peer_slackers: HashMap<PublicKey, Vec<&Finding>> = HashMap::new();
+    for finding in single_findings {
+        peer_slackers
+            .entry(finding.peer_id)
+            .or_default()
+            .push(finding);
+    }
+
+    let mut sections = groups
+        .into_iter()
+        .map(|g| Message::plain(String::new(), g))
+        .collect::<Vec<_>>();
+    for (p, s) in peer_slackers {
+        let alias = rpc
+            .call_typed(&ListnodesRequest { id: Some(p) })
+            .await
+            .ok()
+            .and_then(|n| n.nodes.into_iter().next())
+            .and_then(|n| n.alias);
+        let header = if let Some(alias) = &alias {
+            format!("{} ({}):", p, alias)
+        } else {
+            format!("{}:", p)
+        };
+        let mut lines = vec![Message::plain(String::new(), header)];
+        for finding in s {
+            let id = plugin
+                .state()
+                .findings
+                .lock()
+                .get(&finding.key())
+                .map(|r| r.id);
+            lines.push(render_finding(templates, finding, alias.as_deref(), id));
         }
+        sections.push(join(&lines, "\n"));
     }
-    if !skip_sleep {
-        time::sleep(Duration::from_secs(600)).await;
+    let checked = findings
+        .iter()
+        .filter(|f| f.code != FindingCode::ReconnectFailed)
+        .collect::<Vec<_>>();
+    let severity = checked
+        .iter()
+        .map(|f| f.code.severity())
+        .max()
+        .unwrap_or(Severity::Warning);
+    let body = join(&sections, "\n\n");
+    let message = templates.render(
+        "channel-report",
+        &[
+            ("count", checked.len().to_string()),
+            ("severity", severity.to_string()),
+        ],
+        Some(("findings", &body)),
+        Message::plain("Channel check report\n".to_string(), body.text.clone()),
+    );
+    (severity, message)
+}
+
+/// Render a finding with the template named after its code, e.g. `lost-state.txt`
+fn render_finding(
+    templates: &Templates,
+    finding: &Finding,
+    alias: Option<&str>,
+    id: Option<u64>,
+) -> Message {
+    let mut vars = vec![
+        ("id", id.map(|i| i.to_string()).unwrap_or_default()),
+        ("code", finding.code.to_string()),
+        ("severity", finding.code.severity().to_string()),
+        ("peer_id", finding.peer_id.to_string()),
+        ("alias", alias.unwrap_or_default().to_string()),
+        (
+            "scid",
+            finding.scid.map(|s| s.to_string()).unwrap_or_default(),
+        ),
+        ("message", finding.message.clone()),
+    ];
+    vars.extend(finding.vars.iter().cloned());
+    templates.render(
+        &finding.code.to_string(),
+        &vars,
+        None,
+        Message::plain(
+            String::new(),
+            match id {
+                Some(id) => format!("#{} {}", id, finding.message),
+                None => finding.message.clone(),
+            },
+        ),
+    )
+}
+
+pub fn add_finding(
+    findings: &mut Vec<Finding>,
+    code: FindingCode,
+    chan: &ListpeerchannelsChannels,
+    message: String,
+    vars: Vec<(&'static str, String)>,
+) {
+    findings.push(Finding {
+        code,
+        peer_id: chan.peer_id,
+        scid: chan.short_channel_id,
+        message,
+        vars,
+    });
+}
+
+pub async fn check_channels_loop(plugin: Plugin<PluginState>) -> Result<(), Error> {
+    // Only wait for the node to settle after startup, not when the task restarts
+    if !is_test_debug() {
+        time::sleep(STARTUP_GRACE.saturating_sub(plugin.state().startup.elapsed())).await;
     }
 
     loop {
         {
-            match check_channel(plugin.clone()).await {
+            match AssertUnwindSafe(check_channel(plugin.clone(), CheckScope::All))
+                .catch_unwind()
+                .await
+                .unwrap_or_else(|panic| Err(anyhow!("panicked: {}", panic_message(&panic))))
+            {
                 Ok(_succ) => (),
                 Err(e) => {
                     warn!("Error in check_channel: {}", e);
-                    let config = plugin.state().config.lock().clone();
-                    let subject = "Channel check error".to_string();
-                    let body = e.to_string();
-                    if config.send_mail {
-                        if let Err(e) = send_mail(&config, &subject, &body, false).await {
-                            warn!("check_channels_loop: Error sending mail: {}", e);
-                        };
-                    }
-                    if config.send_telegram {
-                        if let Err(e) = send_telegram(&config, &subject, &body).await {
-                            warn!("check_channels_loop: Error sending telegram: {}", e);
-                        };
-                    }
+                    let message = Templates::load(&plugin).await.render(
+                        "check-error",
+                        &[("error", e.to_string())],
+                        None,
+                        Message::plain("Channel check error".to_string(), e.to_string()),
+                    );
+                    notify(&plugin, Severity::Warning, message).await;
                 }
             };
         }
@@ -0,0 +1,432 @@
+use std::collections::HashMap;
+
+use anyhow::Error;
+use chrono::Utc;
+use cln_plugin::Plugin;
+use cln_rpc::{
+    model::{
+        requests::{FeeratesRequest, FeeratesStyle, ListclosedchannelsRequest},
+        responses::{
+            ListclosedchannelsClosedchannels,
+            ListclosedchannelsClosedchannelsCloseCause,
+            ListpeerchannelsChannels,
+        },
+    },
+    primitives::{ChannelSide, ChannelState},
+    ClnRpc,
+};
+use log::{info, warn};
+use parking_lot::Mutex;
+
+use crate::{
+    channelwatch::add_finding,
+    ignore::is_ignored,
+    maintenance::maintenance_until,
+    mute::is_muted,
+    notify::notify_targets,
+    routing::route,
+    structs::{
+        Config,
+        Finding,
+        FindingCode,
+        IgnoreEntry,
+        Message,
+        PluginState,
+        Severity,
+        StateSince,
+    },
+    templates::Templates,
+    util::{
+        datastore_load,
+        datastore_save,
+        make_rpc_path,
+        sat_per_vbyte,
+        state_changes,
+        state_since,
+    },
+};
+
+const DATASTORE_KEY: &str = "closures";
+
+/// Load the channels we already notified about. On the first start every channel
+/// in `listclosedchannels` counts as notified, so we don't report old closures.
+pub async fn load_closures(rpc: &mut ClnRpc) -> Result<Vec<String>, Error> {
+    if let Some(closures) = datastore_load(rpc, DATASTORE_KEY).await? {
+        return Ok(serde_json::from_str(&closures)?);
+    }
+    let closures = rpc
+        .call_typed(&ListclosedchannelsRequest { id: None })
+        .await?
+        .closedchannels
+        .into_iter()
+        .map(|c| c.channel_id.to_string())
+        .collect::<Vec<_>>();
+    datastore_save(rpc, DATASTORE_KEY, serde_json::to_string(&closures)?).await?;
+    Ok(closures)
+}
+
+async fn save_closures(plugin: &Plugin<PluginState>) {
+    let result = async {
+        let closures = serde_json::to_string(&*plugin.state().closures.lock())?;
+        let mut rpc = ClnRpc::new(make_rpc_path(plugin)).await?;
+        datastore_save(&mut rpc, DATASTORE_KEY, closures).await
+    }
+    .await;
+    if let Err(e) = result {
+        warn!("Error saving notified closures: {}", e);
+    }
+}
+
+/// A channel closure to notify about
+struct Closure {
+    channel_id: String,
+    finding: Finding,
+    severity: Severity,
+}
+
+/// Severity of a closure by its cause, a peer force-closing on us is the worst case
+fn cause_severity(cause: &str) -> Severity {
+    match cause {
+        "user" => Severity::Info,
+        "local" | "protocol" => Severity::Warning,
+        _ => Severity::Critical,
+    }
+}
+
+fn closer_name(closer: Option<ChannelSide>) -> &'static str {
+    match closer {
+        Some(ChannelSide::LOCAL) => "us",
+        Some(ChannelSide::REMOTE) => "the peer",
+        None => "an unknown party",
+    }
+}
+
+/// Cause and message of the last state change and whether the channel went
+/// through a cooperative close
+async fn close_reason(
+    rpc: &mut ClnRpc,
+    chan: &ListpeerchannelsChannels,
+) -> Result<(String, String, bool), Error> {
+    let state_changes = state_changes(rpc, chan).await?;
+    let cooperative = state_changes.iter().any(|s| {
+        s.get("new_state")
+            .and_then(|n| n.as_str())
+            .is_some_and(|n| n.starts_with("CLOSINGD"))
+    });
+    let last = state_changes.last();
+    let field = |name: &str| {
+        last.and_then(|l| l.get(name))
+            .and_then(|v| v.as_str())
+            .unwrap_or("unknown")
+            .to_string()
+    };
+    Ok((field("cause"), field("message"), cooperative))
+}
+
+/// Our funds that are locked by timelocks and the blocks until the last one
+/// expires: in-flight htlcs, plus our balance if we broadcast our commitment
+fn locked_funds(chan: &ListpeerchannelsChannels, current_blockheight: u32) -> (u64, u32) {
+    let htlcs = chan.htlcs.clone().unwrap_or_default();
+    let mut msat = htlcs.iter().map(|h| h.amount_msat.msat()).sum::<u64>();
+    let mut blocks = htlcs
+        .iter()
+        .map(|h| h.expiry.saturating_sub(current_blockheight))
+        .max()
+        .unwrap_or_default();
+    if chan.closer == Some(ChannelSide::LOCAL) {
+        msat += chan.to_us_msat.map(|a| a.msat()).unwrap_or_default();
+        blocks = blocks.max(chan.their_to_self_delay.unwrap_or_default());
+    }
+    (msat, blocks)
+}
+
+async fn closing_channel(
+    rpc: &mut ClnRpc,
+    chan: &ListpeerchannelsChannels,
+    current_blockheight: u32,
+) -> Result<Option<Closure>, Error> {
+    let (cause, reason, cooperative) = close_reason(rpc, chan).await?;
+    if cooperative {
+        return Ok(None);
+    }
+    let closer = closer_name(chan.closer);
+    let (locked_msat, locked_blocks) = locked_funds(chan, current_blockheight);
+    let status = chan.status.clone().unwrap_or_default().join(", ");
+    let mut message = format!(
+        "Channel force-closed by {} ({}): {}. State: {:?}.",
+        closer, cause, reason, chan.state
+    );
+    if locked_msat > 0 {
+        message.push_str(&format!(
+            " {} sats locked by timelocks for up to {} blocks.",
+            locked_msat / 1_000,
+            locked_blocks
+        ));
+    }
+    if !status.is_empty() {
+        message.push_str(&format!(" Status: {}", status));
+    }
+    Ok(Some(Closure {
+        channel_id: chan.channel_id.map(|c| c.to_string()).unwrap_or_default(),
+        severity: cause_severity(&cause),
+        finding: Finding {
+            code: FindingCode::ChannelClosed,
+            peer_id: chan.peer_id,
+            scid: chan.short_channel_id,
+            message,
+            vars: vec![
+                ("closer", closer.to_string()),
+                ("cause", cause),
+                ("reason", reason),
+                ("state", format!("{:?}", chan.state)),
+                ("locked_sat", (locked_msat / 1_000).to_string()),
+                ("locked_blocks", locked_blocks.to_string()),
+                ("status", status),
+            ],
+        },
+    }))
+}
+
+fn closed_channel(chan: &ListclosedchannelsClosedchannels) -> Option<Closure> {
+    let cause = match chan.close_cause {
+        ListclosedchannelsClosedchannelsCloseCause::REMOTE => "remote",
+        ListclosedchannelsClosedchannelsCloseCause::ONCHAIN => "onchain",
+        _ => return None,
+    };
+    let peer_id = chan.peer_id?;
+    let closer = closer_name(chan.closer);
+    let final_sat = chan.final_to_us_msat.msat() / 1_000;
+    Some(Closure {
+        channel_id: chan.channel_id.to_string(),
+        severity: cause_severity(cause),
+        finding: Finding {
+            code: FindingCode::ChannelClosed,
+            peer_id,
+            scid: chan.short_channel_id,
+            message: format!(
+                "Channel closed by {} ({}), {} sats went back to us.",
+                closer, cause, final_sat
+            ),
+            vars: vec![
+                ("closer", closer.to_string()),
+                ("cause", cause.to_string()),
+                ("final_sat", final_sat.to_string()),
+            ],
+        },
+    })
+}
+
+/// Notify about channels that were force-closed or closed unexpectedly since the
+/// last check. Closed channels are only looked up in `listclosedchannels` if
+/// `with_closed` is set, to catch closures we missed while we were not running.
+/// Closures during a maintenance window are notified once it is over.
+#[allow(clippy::too_many_arguments)]
+pub async fn check_closures(
+    plugin: &Plugin<PluginState>,
+    rpc: &mut ClnRpc,
+    config: &Config,
+    ignores: &[IgnoreEntry],
+    channels: &[ListpeerchannelsChannels],
+    with_closed: bool,
+    current_blockheight: u32,
+) -> Result<(), Error> {
+    let notified = plugin.state().closures.lock().clone();
+    let is_new = |channel_id: Option<String>| channel_id.is_some_and(|c| !notified.contains(&c));
+
+    let mut closures = Vec::new();
+    for chan in channels.iter().filter(|c| {
+        matches!(
+            c.state,
+            ChannelState::AWAITING_UNILATERAL
+                | ChannelState::FUNDING_SPEND_SEEN
+                | ChannelState::ONCHAIN
+        ) && is_new(c.channel_id.map(|i| i.to_string()))
+    }) {
+        match closing_channel(rpc, chan, current_blockheight).await? {
+            Some(closure) => closures.push(closure),
+            None => plugin
+                .state()
+                .closures
+                .lock()
+                .push(chan.channel_id.map(|c| c.to_string()).unwrap_or_default()),
+        }
+    }
+    if with_closed {
+        let closed = rpc
+            .call_typed(&ListclosedchannelsRequest { id: None })
+            .await?
+            .closedchannels;
+        for chan in closed
+            .iter()
+            .filter(|c| is_new(Some(c.channel_id.to_string())))
+        {
+            if !closures
+                .iter()
+                .any(|c| c.channel_id == chan.channel_id.to_string())
+            {
+                match closed_channel(chan) {
+                    Some(closure) => closures.push(closure),
+                    None => plugin
+                        .state()
+                        .closures
+                        .lock()
+                        .push(chan.channel_id.to_string()),
+                }
+            }
+        }
+    }
+    if closures.is_empty() {
+        return Ok(());
+    }
+    if let Some(until) = maintenance_until(plugin) {
+        info!(
+            "check_closures: Maintenance until {}, notifying about {} closures afterwards",
+            until,
+            closures.len()
+        );
+        return Ok(());
+    }
+
+    let templates = Templates::load(plugin).await;
+    let mutes = plugin.state().mutes.lock().clone();
+    for closure in &closures {
+        plugin
+            .state()
+            .closures
+            .lock()
+            .push(closure.channel_id.clone());
+        let finding = &closure.finding;
+        warn!(
+            "check_closures: {} {}: {}",
+            finding.peer_id, closure.channel_id, finding.message
+        );
+        if is_ignored(config, ignores, &finding.peer_id, finding.scid) || is_muted(&mutes, finding)
+        {
+            info!(
+                "check_closures: Not notifying about ignored or muted closure of {}",
+                closure.channel_id
+            );
+            continue;
+        }
+        let mut vars = vec![
+            ("peer_id", finding.peer_id.to_string()),
+            ("channel_id", closure.channel_id.clone()),
+            (
+                "scid",
+                finding.scid.map(|s| s.to_string()).unwrap_or_default(),
+            ),
+            ("message", finding.message.clone()),
+        ];
+        vars.extend(finding.vars.iter().cloned());
+        let scid = finding
+            .scid
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| closure.channel_id.clone());
+        let message = templates.render(
+            &finding.code.to_string(),
+            &vars,
+            None,
+            Message::plain(
+                format!("Channel {} closed", scid),
+                format!("{}:\n{}", finding.peer_id, finding.message),
+            ),
+        );
+        let targets = route(
+            config,
+            closure.severity,
+            Some((finding.code, finding.peer_id)),
+        );
+        notify_targets(plugin, closure.severity, targets, message).await;
+    }
+    save_closures(plugin).await;
+    Ok(())
+}
+
+/// Estimated weight of a cooperative closing transaction with two outputs
+const CLOSING_TX_WEIGHT: u64 = 672;
+
+/// Flag cooperative closes whose negotiation didn't progress within
+/// `vitality-stuck-close-after` or whose closing transaction didn't confirm
+/// within `vitality-stuck-close-blocks`, with their closing feerate compared to
+/// CLN's current estimate
+pub async fn check_stuck_closes(
+    rpc: &mut ClnRpc,
+    config: &Config,
+    channels: &[ListpeerchannelsChannels],
+    since: &Mutex<HashMap<String, StateSince>>,
+    findings: &mut Vec<Finding>,
+    current_blockheight: u32,
+) -> Result<(), Error> {
+    let now = Utc::now().timestamp();
+    let mut stuck = Vec::new();
+    for chan in channels {
+        if !matches!(
+            chan.state,
+            ChannelState::CHANNELD_SHUTTING_DOWN
+                | ChannelState::CLOSINGD_SIGEXCHANGE
+                | ChannelState::CLOSINGD_COMPLETE
+        ) {
+            continue;
+        }
+        let Some(progress) = state_since(rpc, chan, since, current_blockheight).await? else {
+            continue;
+        };
+        let blocks = current_blockheight.saturating_sub(progress.since_block);
+        let elapsed = (now - progress.since).max(0) as u64;
+        let is_stuck = if chan.state == ChannelState::CLOSINGD_COMPLETE {
+            config.stuck_close_blocks > 0 && blocks >= config.stuck_close_blocks
+        } else {
+            config.stuck_close_after > 0 && elapsed >= config.stuck_close_after
+        };
+        if is_stuck {
+            stuck.push((chan, blocks, elapsed));
+        }
+    }
+    if stuck.is_empty() {
+        return Ok(());
+    }
+
+    let estimate = rpc
+        .call_typed(&FeeratesRequest {
+            style: FeeratesStyle::PERKW,
+        })
+        .await?
+        .perkw
+        .and_then(|f| f.mutual_close)
+        .map(|f| sat_per_vbyte(f as u64))
+        .unwrap_or_else(|| "unknown".to_string());
+    for (chan, blocks, elapsed) in stuck {
+        let feerate = chan
+            .last_tx_fee_msat
+            .map(|f| sat_per_vbyte(f.msat() / CLOSING_TX_WEIGHT))
+            .unwrap_or_else(|| "unknown".to_string());
+        let mut message = if chan.state == ChannelState::CLOSINGD_COMPLETE {
+            format!("Cooperative close not confirmed after {} blocks.", blocks)
+        } else {
+            format!(
+                "Cooperative close stuck in {:?} for {}h.",
+                chan.state,
+                elapsed / 3_600
+            )
+        };
+        message.push_str(&format!(
+            " Closing feerate ~{} sat/vB, CLN estimates {} sat/vB for mutual closes.",
+            feerate, estimate
+        ));
+        warn!("check_channel: {} {}", chan.peer_id, message);
+        add_finding(
+            findings,
+            FindingCode::StuckClose,
+            chan,
+            message,
+            vec![
+                ("state", format!("{:?}", chan.state)),
+                ("blocks", blocks.to_string()),
+                ("hours", (elapsed / 3_600).to_string()),
+                ("feerate", feerate),
+                ("estimate", estimate.clone()),
+            ],
+        );
+    }
+    Ok(())
+}
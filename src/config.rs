@@ -1,23 +1,62 @@
+use std::str::FromStr;
+
 use anyhow::{anyhow, Error};
+use chrono_tz::Tz;
 use cln_plugin::{options, ConfiguredPlugin, Plugin};
-use cln_rpc::{model::responses::GetinfoResponse, RpcError};
+use cln_rpc::{
+    model::responses::GetinfoResponse,
+    primitives::{PublicKey, ShortChannelId},
+    RpcError,
+};
 use log::info;
 use serde_json::json;
 
 use crate::{
+    ratelimit::parse_rate_limits,
+    routing::{parse_peer_groups, parse_routes, parse_targets},
+    schedule::{parse_time, parse_weekly, parse_windows, QuietHours},
     structs::Config,
-    util::at_or_above_version,
+    tasks::sync_tasks,
+    util::{at_or_above_version, parse_duration},
     PluginState,
     OPT_AMBOSS,
+    OPT_CHANNEL_MIN_RATIO,
+    OPT_DEPLETED_AFTER,
     OPT_EMAIL_FROM,
     OPT_EMAIL_TO,
+    OPT_ESCALATE_AFTER,
+    OPT_ESCALATE_TO,
     OPT_EXPIRING_HTLCS,
+    OPT_EXPIRING_HTLCS_OUT,
+    OPT_FLAP_LIMIT,
+    OPT_FUNDING_UNCONFIRMED_BLOCKS,
+    OPT_HTLC_FORCE_CLOSE,
+    OPT_IGNORE_CHANNELS,
+    OPT_IGNORE_PEERS,
+    OPT_LABEL,
+    OPT_MAIL_RATE_LIMIT,
+    OPT_MAINTENANCE_WINDOWS,
+    OPT_NODE_MIN_RATIO,
+    OPT_OFFLINE_THRESHOLDS,
+    OPT_OUTBOX_FALLBACK_AFTER,
+    OPT_PEER_GROUPS,
+    OPT_QUIET_HOURS,
+    OPT_REPORT_DAILY,
+    OPT_REPORT_WEEKLY,
+    OPT_ROUTES,
     OPT_SMTP_PASSWORD,
     OPT_SMTP_PORT,
     OPT_SMTP_SERVER,
     OPT_SMTP_USERNAME,
+    OPT_STUCK_CLOSE_AFTER,
+    OPT_STUCK_CLOSE_BLOCKS,
+    OPT_STUCK_HTLC_AFTER,
+    OPT_TELEGRAM_ACK,
+    OPT_TELEGRAM_RATE_LIMIT,
     OPT_TELEGRAM_TOKEN,
     OPT_TELEGRAM_USERNAMES,
+    OPT_TEMPLATES_DIR,
+    OPT_TIMEZONE,
     OPT_WATCH_CHANNELS,
     OPT_WATCH_GOSSIP,
 };
@@ -63,13 +102,26 @@ pub async fn setconfig_callback(
 
     activate_mail(&mut config);
     activate_telegram(&mut config);
+    drop(config);
+
+    sync_tasks(&plugin, Some(name));
 
     Ok(json!({}))
 }
 
 fn parse_option(name: &str, value: &serde_json::Value) -> Result<options::Value, Error> {
     match name {
-        n if n.eq(OPT_EXPIRING_HTLCS) || n.eq(OPT_SMTP_PORT) => {
+        n if n.eq(OPT_EXPIRING_HTLCS)
+            || n.eq(OPT_EXPIRING_HTLCS_OUT)
+            || n.eq(OPT_HTLC_FORCE_CLOSE)
+            || n.eq(OPT_STUCK_CLOSE_BLOCKS)
+            || n.eq(OPT_FUNDING_UNCONFIRMED_BLOCKS)
+            || n.eq(OPT_CHANNEL_MIN_RATIO)
+            || n.eq(OPT_NODE_MIN_RATIO)
+            || n.eq(OPT_FLAP_LIMIT)
+            || n.eq(OPT_SMTP_PORT)
+            || n.eq(OPT_OUTBOX_FALLBACK_AFTER) =>
+        {
             if let Some(n_i64) = value.as_i64() {
                 return Ok(options::Value::Integer(n_i64));
             } else if let Some(n_str) = value.as_str() {
@@ -79,7 +131,11 @@ fn parse_option(name: &str, value: &serde_json::Value) -> Result<options::Value,
             }
             Err(anyhow!("{} is not a valid integer!", name))
         }
-        n if n.eq(OPT_AMBOSS) || n.eq(OPT_WATCH_CHANNELS) || n.eq(OPT_WATCH_GOSSIP) => {
+        n if n.eq(OPT_AMBOSS)
+            || n.eq(OPT_WATCH_CHANNELS)
+            || n.eq(OPT_WATCH_GOSSIP)
+            || n.eq(OPT_TELEGRAM_ACK) =>
+        {
             if let Some(n_bool) = value.as_bool() {
                 return Ok(options::Value::Boolean(n_bool));
             } else if let Some(n_str) = value.as_str() {
@@ -107,6 +163,9 @@ pub async fn get_startup_options(
     let mut config = state.config.lock();
 
     config.is_at_or_above_24_11 = at_or_above_version(&info.version, "24.11")?;
+    config.node_alias = info.alias.clone();
+    config.node_id = info.id.to_string();
+    config.network = info.network.clone();
 
     if let Some(utf8) = plugin.option_str(OPT_AMBOSS)? {
         check_option(&mut config, OPT_AMBOSS, &utf8)?;
@@ -114,6 +173,39 @@ pub async fn get_startup_options(
     if let Some(exp) = plugin.option_str(OPT_EXPIRING_HTLCS)? {
         check_option(&mut config, OPT_EXPIRING_HTLCS, &exp)?;
     };
+    if let Some(exp) = plugin.option_str(OPT_EXPIRING_HTLCS_OUT)? {
+        check_option(&mut config, OPT_EXPIRING_HTLCS_OUT, &exp)?;
+    };
+    if let Some(blocks) = plugin.option_str(OPT_HTLC_FORCE_CLOSE)? {
+        check_option(&mut config, OPT_HTLC_FORCE_CLOSE, &blocks)?;
+    };
+    if let Some(blocks) = plugin.option_str(OPT_STUCK_CLOSE_BLOCKS)? {
+        check_option(&mut config, OPT_STUCK_CLOSE_BLOCKS, &blocks)?;
+    };
+    if let Some(after) = plugin.option_str(OPT_STUCK_CLOSE_AFTER)? {
+        check_option(&mut config, OPT_STUCK_CLOSE_AFTER, &after)?;
+    };
+    if let Some(blocks) = plugin.option_str(OPT_FUNDING_UNCONFIRMED_BLOCKS)? {
+        check_option(&mut config, OPT_FUNDING_UNCONFIRMED_BLOCKS, &blocks)?;
+    };
+    if let Some(after) = plugin.option_str(OPT_STUCK_HTLC_AFTER)? {
+        check_option(&mut config, OPT_STUCK_HTLC_AFTER, &after)?;
+    };
+    if let Some(ratio) = plugin.option_str(OPT_CHANNEL_MIN_RATIO)? {
+        check_option(&mut config, OPT_CHANNEL_MIN_RATIO, &ratio)?;
+    };
+    if let Some(after) = plugin.option_str(OPT_DEPLETED_AFTER)? {
+        check_option(&mut config, OPT_DEPLETED_AFTER, &after)?;
+    };
+    if let Some(ratio) = plugin.option_str(OPT_NODE_MIN_RATIO)? {
+        check_option(&mut config, OPT_NODE_MIN_RATIO, &ratio)?;
+    };
+    if let Some(thresholds) = plugin.option_str(OPT_OFFLINE_THRESHOLDS)? {
+        check_option(&mut config, OPT_OFFLINE_THRESHOLDS, &thresholds)?;
+    };
+    if let Some(limit) = plugin.option_str(OPT_FLAP_LIMIT)? {
+        check_option(&mut config, OPT_FLAP_LIMIT, &limit)?;
+    };
     if let Some(watch) = plugin.option_str(OPT_WATCH_CHANNELS)? {
         check_option(&mut config, OPT_WATCH_CHANNELS, &watch)?;
     };
@@ -144,6 +236,57 @@ pub async fn get_startup_options(
     if let Some(emailto) = plugin.option_str(OPT_EMAIL_TO)? {
         check_option(&mut config, OPT_EMAIL_TO, &emailto)?;
     };
+    if let Some(ignorepeers) = plugin.option_str(OPT_IGNORE_PEERS)? {
+        check_option(&mut config, OPT_IGNORE_PEERS, &ignorepeers)?;
+    };
+    if let Some(ignorechannels) = plugin.option_str(OPT_IGNORE_CHANNELS)? {
+        check_option(&mut config, OPT_IGNORE_CHANNELS, &ignorechannels)?;
+    };
+    if let Some(windows) = plugin.option_str(OPT_MAINTENANCE_WINDOWS)? {
+        check_option(&mut config, OPT_MAINTENANCE_WINDOWS, &windows)?;
+    };
+    if let Some(quiet) = plugin.option_str(OPT_QUIET_HOURS)? {
+        check_option(&mut config, OPT_QUIET_HOURS, &quiet)?;
+    };
+    if let Some(tz) = plugin.option_str(OPT_TIMEZONE)? {
+        check_option(&mut config, OPT_TIMEZONE, &tz)?;
+    };
+    if let Some(fallback) = plugin.option_str(OPT_OUTBOX_FALLBACK_AFTER)? {
+        check_option(&mut config, OPT_OUTBOX_FALLBACK_AFTER, &fallback)?;
+    };
+    if let Some(limits) = plugin.option_str(OPT_MAIL_RATE_LIMIT)? {
+        check_option(&mut config, OPT_MAIL_RATE_LIMIT, &limits)?;
+    };
+    if let Some(limits) = plugin.option_str(OPT_TELEGRAM_RATE_LIMIT)? {
+        check_option(&mut config, OPT_TELEGRAM_RATE_LIMIT, &limits)?;
+    };
+    if let Some(label) = plugin.option_str(OPT_LABEL)? {
+        check_option(&mut config, OPT_LABEL, &label)?;
+    };
+    if let Some(routes) = plugin.option_str(OPT_ROUTES)? {
+        check_option(&mut config, OPT_ROUTES, &routes)?;
+    };
+    if let Some(groups) = plugin.option_str(OPT_PEER_GROUPS)? {
+        check_option(&mut config, OPT_PEER_GROUPS, &groups)?;
+    };
+    if let Some(after) = plugin.option_str(OPT_ESCALATE_AFTER)? {
+        check_option(&mut config, OPT_ESCALATE_AFTER, &after)?;
+    };
+    if let Some(targets) = plugin.option_str(OPT_ESCALATE_TO)? {
+        check_option(&mut config, OPT_ESCALATE_TO, &targets)?;
+    };
+    if let Some(ack) = plugin.option_str(OPT_TELEGRAM_ACK)? {
+        check_option(&mut config, OPT_TELEGRAM_ACK, &ack)?;
+    };
+    if let Some(dir) = plugin.option_str(OPT_TEMPLATES_DIR)? {
+        check_option(&mut config, OPT_TEMPLATES_DIR, &dir)?;
+    };
+    if let Some(daily) = plugin.option_str(OPT_REPORT_DAILY)? {
+        check_option(&mut config, OPT_REPORT_DAILY, &daily)?;
+    };
+    if let Some(weekly) = plugin.option_str(OPT_REPORT_WEEKLY)? {
+        check_option(&mut config, OPT_REPORT_WEEKLY, &weekly)?;
+    };
 
     activate_mail(&mut config);
     activate_telegram(&mut config);
@@ -178,12 +321,76 @@ fn activate_telegram(config: &mut Config) {
     }
 }
 
+pub fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(|v| v.trim()).filter(|v| !v.is_empty())
+}
+
+fn parse_percent(name: &str, value: &options::Value) -> Result<u32, Error> {
+    let percent = u32::try_from(value.as_i64().unwrap())?;
+    if percent > 100 {
+        return Err(anyhow!("{} must be a percentage from 0 to 100", name));
+    }
+    Ok(percent)
+}
+
 fn check_option(config: &mut Config, name: &str, value: &options::Value) -> Result<(), Error> {
     match name {
         n if n.eq(OPT_AMBOSS) => config.amboss = value.as_bool().unwrap(),
         n if n.eq(OPT_EXPIRING_HTLCS) => {
             config.expiring_htlcs = u32::try_from(value.as_i64().unwrap())?
         }
+        n if n.eq(OPT_EXPIRING_HTLCS_OUT) => {
+            config.expiring_htlcs_out = u32::try_from(value.as_i64().unwrap())?
+        }
+        n if n.eq(OPT_HTLC_FORCE_CLOSE) => {
+            config.htlc_force_close = u32::try_from(value.as_i64().unwrap())?
+        }
+        n if n.eq(OPT_STUCK_CLOSE_BLOCKS) => {
+            config.stuck_close_blocks = u32::try_from(value.as_i64().unwrap())?
+        }
+        n if n.eq(OPT_STUCK_CLOSE_AFTER) => {
+            let after = value.as_str().unwrap().trim();
+            config.stuck_close_after = if after.is_empty() {
+                0
+            } else {
+                parse_duration(after)?
+            }
+        }
+        n if n.eq(OPT_FUNDING_UNCONFIRMED_BLOCKS) => {
+            config.funding_unconfirmed_blocks = u32::try_from(value.as_i64().unwrap())?
+        }
+        n if n.eq(OPT_STUCK_HTLC_AFTER) => {
+            let after = value.as_str().unwrap().trim();
+            config.stuck_htlc_after = if after.is_empty() {
+                0
+            } else {
+                parse_duration(after)?
+            }
+        }
+        n if n.eq(OPT_CHANNEL_MIN_RATIO) => config.channel_min_ratio = parse_percent(n, value)?,
+        n if n.eq(OPT_DEPLETED_AFTER) => {
+            let after = value.as_str().unwrap().trim();
+            config.depleted_after = if after.is_empty() {
+                0
+            } else {
+                parse_duration(after)?
+            }
+        }
+        n if n.eq(OPT_NODE_MIN_RATIO) => config.node_min_ratio = parse_percent(n, value)?,
+        n if n.eq(OPT_OFFLINE_THRESHOLDS) => {
+            let mut thresholds = value
+                .as_str()
+                .unwrap()
+                .split(',')
+                .map(str::trim)
+                .filter(|t| !t.is_empty())
+                .map(parse_duration)
+                .collect::<Result<Vec<_>, _>>()?;
+            thresholds.sort();
+            thresholds.dedup();
+            config.offline_thresholds = thresholds
+        }
+        n if n.eq(OPT_FLAP_LIMIT) => config.flap_limit = u32::try_from(value.as_i64().unwrap())?,
         n if n.eq(OPT_WATCH_CHANNELS) => config.watch_channels = value.as_bool().unwrap(),
         n if n.eq(OPT_WATCH_GOSSIP) => config.watch_gossip = value.as_bool().unwrap(),
         n if n.eq(OPT_TELEGRAM_TOKEN) => {
@@ -201,6 +408,82 @@ fn check_option(config: &mut Config, name: &str, value: &options::Value) -> Resu
         n if n.eq(OPT_SMTP_PORT) => config.smtp_port = u16::try_from(value.as_i64().unwrap())?,
         n if n.eq(OPT_EMAIL_FROM) => config.email_from = value.as_str().unwrap().to_string(),
         n if n.eq(OPT_EMAIL_TO) => config.email_to = value.as_str().unwrap().to_string(),
+        n if n.eq(OPT_IGNORE_PEERS) => {
+            config.ignore_peers = split_list(value.as_str().unwrap())
+                .map(|p| {
+                    PublicKey::from_str(p)
+                        .map_err(|e| anyhow!("{} is not a valid node id: {}", p, e))
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        }
+        n if n.eq(OPT_IGNORE_CHANNELS) => {
+            config.ignore_channels = split_list(value.as_str().unwrap())
+                .map(|c| {
+                    ShortChannelId::from_str(c)
+                        .map_err(|e| anyhow!("{} is not a valid short channel id: {}", c, e))
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        }
+        n if n.eq(OPT_MAINTENANCE_WINDOWS) => {
+            config.maintenance_windows = parse_windows(value.as_str().unwrap())?
+        }
+        n if n.eq(OPT_QUIET_HOURS) => {
+            let quiet = value.as_str().unwrap().trim();
+            config.quiet_hours = if quiet.is_empty() {
+                None
+            } else {
+                Some(QuietHours::parse(quiet)?)
+            }
+        }
+        n if n.eq(OPT_OUTBOX_FALLBACK_AFTER) => {
+            config.outbox_fallback_after = u32::try_from(value.as_i64().unwrap())?
+        }
+        n if n.eq(OPT_MAIL_RATE_LIMIT) => {
+            config.mail_rate_limits = parse_rate_limits(value.as_str().unwrap())?
+        }
+        n if n.eq(OPT_TELEGRAM_RATE_LIMIT) => {
+            config.telegram_rate_limits = parse_rate_limits(value.as_str().unwrap())?
+        }
+        n if n.eq(OPT_LABEL) => config.label = value.as_str().unwrap().trim().to_string(),
+        n if n.eq(OPT_ROUTES) => config.routes = parse_routes(value.as_str().unwrap())?,
+        n if n.eq(OPT_PEER_GROUPS) => {
+            config.peer_groups = parse_peer_groups(value.as_str().unwrap())?
+        }
+        n if n.eq(OPT_ESCALATE_AFTER) => {
+            let after = value.as_str().unwrap().trim();
+            config.escalate_after = if after.is_empty() {
+                0
+            } else {
+                parse_duration(after)?
+            }
+        }
+        n if n.eq(OPT_ESCALATE_TO) => config.escalate_to = parse_targets(value.as_str().unwrap())?,
+        n if n.eq(OPT_TELEGRAM_ACK) => config.telegram_ack = value.as_bool().unwrap(),
+        n if n.eq(OPT_TEMPLATES_DIR) => {
+            config.templates_dir = value.as_str().unwrap().trim().to_string()
+        }
+        n if n.eq(OPT_REPORT_DAILY) => {
+            let daily = value.as_str().unwrap().trim();
+            config.report_daily = if daily.is_empty() {
+                None
+            } else {
+                Some(parse_time(daily)?)
+            }
+        }
+        n if n.eq(OPT_REPORT_WEEKLY) => {
+            let weekly = value.as_str().unwrap().trim();
+            config.report_weekly = if weekly.is_empty() {
+                None
+            } else {
+                Some(parse_weekly(weekly)?)
+            }
+        }
+        n if n.eq(OPT_TIMEZONE) => {
+            let tz = value.as_str().unwrap().trim();
+            config.timezone = tz
+                .parse::<Tz>()
+                .map_err(|_| anyhow!("{} is not a valid IANA timezone", tz))?
+        }
         _ => return Err(anyhow!("Unknown option: {}", name)),
     }
     Ok(())
@@ -0,0 +1,223 @@
+use std::time::Duration;
+
+use anyhow::Error;
+use chrono::{DateTime, Utc};
+use cln_plugin::Plugin;
+use log::{debug, info, warn};
+use teloxide::{payloads::GetUpdatesSetters, requests::Requester, types::UpdateKind, Bot};
+use tokio::time;
+
+use crate::{
+    maintenance::maintenance_until,
+    mute::{acknowledge, is_muted, parse_id},
+    notify::notify_targets,
+    routing::route,
+    structs::{Backend, Config, FindingRecord, Message, MuteEntry, PluginState, Severity, Target},
+    templates::Templates,
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Longest wait between two escalations of the same finding
+const MAX_INTERVAL_S: u64 = 4 * 3_600;
+/// Long polling timeout for telegram updates
+const TELEGRAM_POLL_S: u32 = 30;
+
+/// Critical findings that are not acknowledged or muted are escalated
+/// `vitality-escalate-after` after they were first seen and again after doubling intervals
+fn escalation_due(record: &FindingRecord, mutes: &[MuteEntry], after: u64, now: i64) -> bool {
+    if record.acknowledged
+        || record.finding.code.severity() < Severity::Critical
+        || is_muted(mutes, &record.finding)
+    {
+        return false;
+    }
+    if record.escalations == 0 {
+        return now - record.first_seen >= after as i64;
+    }
+    let interval = (after << record.escalations.min(16)).min(MAX_INTERVAL_S.max(after));
+    now - record.escalated_at >= interval as i64
+}
+
+/// `vitality-escalate-to` if any of its backends is configured, otherwise where
+/// critical notifications are routed anyway
+fn escalation_targets(config: &Config) -> Vec<Target> {
+    let targets = config
+        .escalate_to
+        .iter()
+        .filter(|t| t.backend.configured(config))
+        .cloned()
+        .collect::<Vec<_>>();
+    if targets.is_empty() {
+        route(config, Severity::Critical, None)
+    } else {
+        targets
+    }
+}
+
+async fn check_escalations(plugin: &Plugin<PluginState>) {
+    let config = plugin.state().config.lock().clone();
+    if config.escalate_after == 0 || maintenance_until(plugin).is_some() {
+        return;
+    }
+    let now = Utc::now().timestamp();
+    let mutes = plugin.state().mutes.lock().clone();
+    let mut due = plugin
+        .state()
+        .findings
+        .lock()
+        .values_mut()
+        .filter(|r| escalation_due(r, &mutes, config.escalate_after, now))
+        .map(|r| {
+            r.escalations += 1;
+            r.escalated_at = now;
+            r.clone()
+        })
+        .collect::<Vec<_>>();
+    if due.is_empty() {
+        return;
+    }
+    due.sort_by_key(|r| r.id);
+    info!("Escalating {} unacknowledged critical findings", due.len());
+
+    let findings = due
+        .iter()
+        .map(|r| {
+            let first_seen = DateTime::from_timestamp(r.first_seen, 0)
+                .map(|t| {
+                    t.with_timezone(&config.timezone)
+                        .format("%H:%M")
+                        .to_string()
+                })
+                .unwrap_or_default();
+            let scid = r
+                .finding
+                .scid
+                .map(|s| format!(" {}", s))
+                .unwrap_or_default();
+            format!(
+                "#{} {} {}{}: {}\nfirst seen {}, escalation {}",
+                r.id,
+                r.finding.code,
+                r.finding.peer_id,
+                scid,
+                r.finding.message,
+                first_seen,
+                r.escalations
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    let ids = due
+        .iter()
+        .map(|r| r.id.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let findings = Message::plain(String::new(), findings);
+    let ack_hint = if config.telegram_ack {
+        " or by sending `/ack <id>` to the telegram bot"
+    } else {
+        ""
+    };
+    let message = Templates::load(plugin).await.render(
+        "escalation",
+        &[("count", due.len().to_string()), ("ids", ids)],
+        Some(("findings", &findings)),
+        Message::plain(
+            format!("Escalation: {} unacknowledged critical findings", due.len()),
+            format!(
+                "{}\n\nAcknowledge with `lightning-cli vitality-ack <id>`{}",
+                findings.text, ack_hint
+            ),
+        ),
+    );
+    notify_targets(
+        plugin,
+        Severity::Critical,
+        escalation_targets(&config),
+        message,
+    )
+    .await;
+}
+
+/// Handle `/ack <id>` commands sent to the telegram bot by the configured chats.
+/// Finding ids are per node and telegram hands each update to only one poller, so
+/// this needs a bot of its own for every node and is off unless `vitality-telegram-ack` is set.
+async fn poll_telegram(plugin: &Plugin<PluginState>, offset: &mut i32) -> Result<(), Error> {
+    let config = plugin.state().config.lock().clone();
+    let bot = Bot::new(config.telegram_token.clone());
+    let updates = bot
+        .get_updates()
+        .offset(*offset)
+        .timeout(TELEGRAM_POLL_S)
+        .await?;
+    let mut chats = Target::new(Backend::Telegram).recipients(&config);
+    for target in config
+        .escalate_to
+        .iter()
+        .filter(|t| t.backend == Backend::Telegram)
+    {
+        chats.extend(target.recipients(&config));
+    }
+
+    for update in updates {
+        *offset = update.id.0 as i32 + 1;
+        let UpdateKind::Message(message) = update.kind else {
+            continue;
+        };
+        // Only `/ack`, not other commands like `/acknowledge`
+        let Some(command) = message
+            .text()
+            .and_then(|t| t.trim().strip_prefix("/ack"))
+            .filter(|c| c.is_empty() || c.starts_with(char::is_whitespace) || c.starts_with('@'))
+        else {
+            continue;
+        };
+        if !chats.contains(&message.chat.id.to_string()) {
+            debug!("Ignoring /ack from unknown chat {}", message.chat.id);
+            continue;
+        }
+        // Strip the bot name of `/ack@mybot 12`
+        let command = match command.strip_prefix('@') {
+            Some(c) => c.split_once(' ').map(|(_, id)| id).unwrap_or_default(),
+            None => command,
+        };
+        let result = match parse_id(command) {
+            Ok(id) => acknowledge(plugin, id).await,
+            Err(e) => Err(e),
+        };
+        let reply = match result {
+            Ok(ids) if ids.is_empty() => "Nothing to acknowledge".to_string(),
+            Ok(ids) => format!(
+                "Acknowledged {}",
+                ids.iter()
+                    .map(|i| format!("#{}", i))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Err(e) => e.to_string(),
+        };
+        if let Err(e) = bot.send_message(message.chat.id, reply).await {
+            warn!("Error replying to /ack in telegram: {}", e);
+        }
+    }
+    Ok(())
+}
+
+pub async fn escalation_loop(plugin: Plugin<PluginState>) -> Result<(), Error> {
+    let mut offset = 0;
+    loop {
+        check_escalations(&plugin).await;
+        let poll = {
+            let config = plugin.state().config.lock();
+            config.send_telegram && config.telegram_ack
+        };
+        if poll {
+            if let Err(e) = poll_telegram(&plugin, &mut offset).await {
+                warn!("Error getting telegram updates: {}", e);
+                time::sleep(CHECK_INTERVAL).await;
+            }
+        } else {
+            time::sleep(CHECK_INTERVAL).await;
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::{str::FromStr, time::Duration};
+
+use anyhow::Error;
+use cln_plugin::Plugin;
+use cln_rpc::primitives::PublicKey;
+use log::{debug, warn};
+use serde_json::Value;
+use tokio::time;
+
+use crate::{
+    channelwatch::{check_channel, CheckScope},
+    peers::record_disconnect,
+    structs::PluginState,
+    tasks::TaskKind,
+    uptime::record_connection,
+    util::{is_test_debug, STARTUP_GRACE},
+};
+
+/// Time for the channel reestablish to finish after a connect
+const CONNECT_SETTLE: Duration = Duration::from_secs(30);
+/// Time for lightningd to try a reconnect after a disconnect
+const DISCONNECT_SETTLE: Duration = Duration::from_secs(60);
+const WARNING_SETTLE: Duration = Duration::from_secs(10);
+
+pub async fn connect_handler(plugin: Plugin<PluginState>, v: Value) -> Result<(), Error> {
+    if let Some(peer) = payload(&v, "connect").get("id").and_then(parse_pubkey) {
+        record_connection(&plugin, peer, true);
+        schedule_check(plugin, CheckScope::Peer(peer), CONNECT_SETTLE);
+    }
+    Ok(())
+}
+
+pub async fn disconnect_handler(plugin: Plugin<PluginState>, v: Value) -> Result<(), Error> {
+    if let Some(peer) = payload(&v, "disconnect").get("id").and_then(parse_pubkey) {
+        record_disconnect(&plugin, peer);
+        record_connection(&plugin, peer, false);
+        schedule_check(plugin, CheckScope::Peer(peer), DISCONNECT_SETTLE);
+    }
+    Ok(())
+}
+
+pub async fn channel_state_changed_handler(
+    plugin: Plugin<PluginState>,
+    v: Value,
+) -> Result<(), Error> {
+    let notification = payload(&v, "channel_state_changed");
+    let old_state = notification
+        .get("old_state")
+        .and_then(|s| s.as_str())
+        .unwrap_or_default();
+    let new_state = notification
+        .get("new_state")
+        .and_then(|s| s.as_str())
+        .unwrap_or_default();
+    if new_state == "CHANNELD_NORMAL" && old_state.ends_with("AWAITING_LOCKIN") {
+        plugin
+            .state()
+            .stats
+            .lock()
+            .record(|s| s.channels_opened += 1);
+    } else if new_state == "ONCHAIN" {
+        plugin
+            .state()
+            .stats
+            .lock()
+            .record(|s| s.channels_closed += 1);
+    }
+    if let Some(peer) = notification.get("peer_id").and_then(parse_pubkey) {
+        debug!(
+            "channel_state_changed: {} {} -> {}",
+            peer, old_state, new_state
+        );
+        schedule_check(plugin, CheckScope::Peer(peer), Duration::ZERO);
+    }
+    Ok(())
+}
+
+pub async fn block_added_handler(plugin: Plugin<PluginState>, _v: Value) -> Result<(), Error> {
+    schedule_check(plugin, CheckScope::Block, Duration::ZERO);
+    Ok(())
+}
+
+pub async fn warning_handler(plugin: Plugin<PluginState>, v: Value) -> Result<(), Error> {
+    let notification = payload(&v, "warning");
+    let text = format!(
+        "{} {}",
+        notification
+            .get("source")
+            .and_then(|s| s.as_str())
+            .unwrap_or_default(),
+        notification
+            .get("log")
+            .and_then(|s| s.as_str())
+            .unwrap_or_default()
+    );
+    if let Some(peer) = find_pubkey(&text) {
+        schedule_check(plugin, CheckScope::Peer(peer), WARNING_SETTLE);
+    }
+    Ok(())
+}
+
+/// Newer CLN versions wrap the notification in an object named after the topic
+fn payload<'a>(v: &'a Value, topic: &str) -> &'a Value {
+    v.get(topic).unwrap_or(v)
+}
+
+fn parse_pubkey(v: &Value) -> Option<PublicKey> {
+    v.as_str().and_then(|s| PublicKey::from_str(s).ok())
+}
+
+fn find_pubkey(text: &str) -> Option<PublicKey> {
+    text.split(|c: char| !c.is_ascii_hexdigit())
+        .filter(|word| word.len() == 66 && (word.starts_with("02") || word.starts_with("03")))
+        .find_map(|word| PublicKey::from_str(word).ok())
+}
+
+fn schedule_check(plugin: Plugin<PluginState>, scope: CheckScope, delay: Duration) {
+    {
+        let config = plugin.state().config.lock();
+        if !TaskKind::ChannelWatch.wanted(&config) {
+            return;
+        }
+    }
+    if !is_test_debug() && plugin.state().startup.elapsed() < STARTUP_GRACE {
+        return;
+    }
+    tokio::spawn(async move {
+        time::sleep(delay).await;
+        if let Err(e) = check_channel(plugin, scope).await {
+            warn!("Error in event check {:?}: {}", scope, e);
+        }
+    });
+}
@@ -0,0 +1,101 @@
+use anyhow::Error;
+use chrono::Utc;
+use cln_plugin::Plugin;
+use serde_json::json;
+
+use crate::{
+    channelwatch::CheckScope,
+    mute::is_muted,
+    structs::{Finding, FindingKey, FindingRecord, PluginState},
+};
+
+/// Update the list of current findings with the results of a check. Findings
+/// covered by the `scope` of the check that were not found again are resolved,
+/// as are their acknowledgements. Returns true if acknowledgements were resolved.
+pub fn record_findings(
+    plugin: &Plugin<PluginState>,
+    scope: CheckScope,
+    findings: &[Finding],
+) -> bool {
+    let now = Utc::now().timestamp();
+    let mut records = plugin.state().findings.lock();
+    let mut acks = plugin.state().acks.lock();
+    let covered = |(code, peer_id, _): &FindingKey| match scope {
+        CheckScope::All => true,
+        CheckScope::Peer(peer) => *peer_id == peer && !code.is_gossip(),
+        CheckScope::Block => !code.is_gossip(),
+    };
+    let resolved = |key: &FindingKey| covered(key) && !findings.iter().any(|f| f.key() == *key);
+
+    let before = records.len();
+    records.retain(|key, _| !resolved(key));
+    let acks_before = acks.len();
+    acks.retain(|key| !resolved(key));
+    let resolved = (before - records.len()) as u64;
+    let raised = findings
+        .iter()
+        .filter(|f| !records.contains_key(&f.key()))
+        .count() as u64;
+    plugin.state().stats.lock().record(|s| {
+        s.findings_raised += raised;
+        s.findings_resolved += resolved;
+    });
+
+    let mut next_id = plugin.state().next_finding_id.lock();
+    for finding in findings {
+        records
+            .entry(finding.key())
+            .and_modify(|r| {
+                r.finding = finding.clone();
+                r.last_seen = now;
+            })
+            .or_insert_with(|| {
+                let id = *next_id;
+                *next_id += 1;
+                FindingRecord {
+                    id,
+                    finding: finding.clone(),
+                    first_seen: now,
+                    last_seen: now,
+                    acknowledged: acks.contains(&finding.key()),
+                    escalations: 0,
+                    escalated_at: 0,
+                }
+            });
+    }
+    acks.len() != acks_before
+}
+
+pub async fn list_findings(
+    plugin: Plugin<PluginState>,
+    _args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let mutes = plugin.state().mutes.lock().clone();
+    let mut records = plugin
+        .state()
+        .findings
+        .lock()
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+    records.sort_by_key(|r| r.first_seen);
+    let findings = records
+        .iter()
+        .map(|r| {
+            json!({
+                "id": r.id,
+                "code": r.finding.code.to_string(),
+                "severity": r.finding.code.severity().to_string(),
+                "peer_id": r.finding.peer_id.to_string(),
+                "short_channel_id": r.finding.scid.map(|s| s.to_string()),
+                "message": r.finding.message,
+                "first_seen": r.first_seen,
+                "last_seen": r.last_seen,
+                "acknowledged": r.acknowledged,
+                "muted": is_muted(&mutes, &r.finding),
+                "escalations": r.escalations,
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(json!({"findings": findings}))
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+
+use anyhow::Error;
+use cln_rpc::{
+    model::{
+        requests::{FeeratesRequest, FeeratesStyle, ListfundsRequest, ListtransactionsRequest},
+        responses::{ListpeerchannelsChannels, ListtransactionsTransactions},
+    },
+    primitives::{ChannelSide, ChannelState},
+    ClnRpc,
+};
+use log::warn;
+use parking_lot::Mutex;
+
+use crate::{
+    channelwatch::add_finding,
+    structs::{Config, Finding, FindingCode, StateSince},
+    util::{sat_per_vbyte, state_since},
+};
+
+/// Weight of a child spending one P2WPKH output to one P2WPKH output
+const CPFP_CHILD_WEIGHT: u64 = 440;
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> Option<usize> {
+    let first = *bytes.get(*pos)?;
+    *pos += 1;
+    let len = match first {
+        0xfd => 2,
+        0xfe => 4,
+        0xff => 8,
+        n => return Some(n as usize),
+    };
+    let value = bytes
+        .get(*pos..*pos + len)?
+        .iter()
+        .rev()
+        .fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+    *pos += len;
+    usize::try_from(value).ok()
+}
+
+/// Weight of a raw transaction, CLN tells us neither the size nor the fee of our
+/// funding transactions
+fn tx_weight(rawtx: &str) -> Option<u64> {
+    let bytes = (0..rawtx.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(rawtx.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    let segwit = bytes.get(4) == Some(&0) && bytes.get(5) == Some(&1);
+    let mut pos = if segwit { 6 } else { 4 };
+    let inputs = read_varint(&bytes, &mut pos)?;
+    for _ in 0..inputs {
+        pos += 36;
+        pos += read_varint(&bytes, &mut pos)? + 4;
+    }
+    let outputs = read_varint(&bytes, &mut pos)?;
+    for _ in 0..outputs {
+        pos += 8;
+        pos += read_varint(&bytes, &mut pos)?;
+    }
+    let witness_start = pos;
+    if segwit {
+        for _ in 0..inputs {
+            for _ in 0..read_varint(&bytes, &mut pos)? {
+                pos += read_varint(&bytes, &mut pos)?;
+            }
+        }
+    }
+    let witness = pos - witness_start + if segwit { 2 } else { 0 };
+    if pos + 4 != bytes.len() {
+        return None;
+    }
+    Some((bytes.len() - witness) as u64 * 3 + bytes.len() as u64)
+}
+
+/// Feerate in perkw of one of our transactions, the inputs must be in our wallet too
+fn tx_feerate(
+    tx: &ListtransactionsTransactions,
+    wallet: &[ListtransactionsTransactions],
+) -> Option<u64> {
+    let mut input_msat = 0;
+    for input in &tx.inputs {
+        input_msat += wallet
+            .iter()
+            .find(|t| t.hash == input.txid)?
+            .outputs
+            .iter()
+            .find(|o| o.index == input.index)?
+            .amount_msat
+            .msat();
+    }
+    let output_msat = tx.outputs.iter().map(|o| o.amount_msat.msat()).sum::<u64>();
+    let fee_sat = input_msat.checked_sub(output_msat)? / 1_000;
+    Some(fee_sat * 1_000 / tx_weight(&tx.rawtx)?)
+}
+
+/// How to get the funding transaction of `chan` confirmed at `target` perkw
+fn bump_command(
+    chan: &ListpeerchannelsChannels,
+    tx: &ListtransactionsTransactions,
+    feerate: Option<u64>,
+    target: u64,
+    change: Option<String>,
+) -> String {
+    let channel_id = chan.channel_id.map(|c| c.to_string()).unwrap_or_default();
+    if chan.state == ChannelState::DUALOPEND_AWAITING_LOCKIN {
+        let amount = chan
+            .inflight
+            .as_ref()
+            .and_then(|i| i.last())
+            .map(|i| i.our_funding_msat.msat() / 1_000)
+            .unwrap_or_default();
+        let utxos = tx
+            .inputs
+            .iter()
+            .map(|i| format!("\"{}:{}\"", i.txid, i.index))
+            .collect::<Vec<_>>()
+            .join(",");
+        return format!(
+            "Bump it with: lightning-cli openchannel_bump {} {}sat $(lightning-cli -k utxopsbt \
+            satoshi={}sat feerate={}perkw startweight=0 utxos='[{}]' reservedok=true \
+            excess_as_change=true | jq -r .psbt) {}perkw, then openchannel_update, signpsbt \
+            and openchannel_signed",
+            channel_id, amount, amount, target, utxos, target
+        );
+    }
+    let Some(change) = change else {
+        return "There is no change output to bump it with CPFP, it can only confirm \
+            when fees go down"
+            .to_string();
+    };
+    // The child pays for the missing fee of the funding transaction too
+    let child = match (feerate, tx_weight(&tx.rawtx)) {
+        (Some(feerate), Some(weight)) if feerate < target => {
+            target + (target - feerate) * weight / CPFP_CHILD_WEIGHT
+        }
+        _ => target,
+    };
+    format!(
+        "Bump it with CPFP: lightning-cli -k withdraw destination=$(lightning-cli newaddr \
+        | jq -r .bech32) satoshi=all feerate={}perkw minconf=0 utxos='[\"{}\"]'",
+        child, change
+    )
+}
+
+/// Flag our channel opens whose funding transaction is unconfirmed for
+/// `vitality-funding-unconfirmed-blocks`, with its feerate compared to CLN's
+/// estimate and the command to bump it
+pub async fn check_funding(
+    rpc: &mut ClnRpc,
+    config: &Config,
+    channels: &[ListpeerchannelsChannels],
+    since: &Mutex<HashMap<String, StateSince>>,
+    findings: &mut Vec<Finding>,
+    current_blockheight: u32,
+) -> Result<(), Error> {
+    if config.funding_unconfirmed_blocks == 0 {
+        return Ok(());
+    }
+    let mut unconfirmed = Vec::new();
+    for chan in channels.iter().filter(|c| {
+        c.opener == ChannelSide::LOCAL
+            && matches!(
+                c.state,
+                ChannelState::CHANNELD_AWAITING_LOCKIN | ChannelState::DUALOPEND_AWAITING_LOCKIN
+            )
+    }) {
+        let Some(opened) = state_since(rpc, chan, since, current_blockheight).await? else {
+            continue;
+        };
+        let blocks = current_blockheight.saturating_sub(opened.since_block);
+        if blocks >= config.funding_unconfirmed_blocks {
+            unconfirmed.push((chan, blocks));
+        }
+    }
+    if unconfirmed.is_empty() {
+        return Ok(());
+    }
+
+    let wallet = rpc
+        .call_typed(&ListtransactionsRequest {})
+        .await?
+        .transactions;
+    let outputs = rpc
+        .call_typed(&ListfundsRequest { spent: None })
+        .await?
+        .outputs;
+    let target = rpc
+        .call_typed(&FeeratesRequest {
+            style: FeeratesStyle::PERKW,
+        })
+        .await?
+        .perkw
+        .and_then(|f| f.opening)
+        .map(u64::from);
+    for (chan, blocks) in unconfirmed {
+        let Some(txid) = chan
+            .inflight
+            .as_ref()
+            .and_then(|i| i.last())
+            .map(|i| i.funding_txid.clone())
+            .or_else(|| chan.funding_txid.clone())
+        else {
+            continue;
+        };
+        // Confirmed and waiting for the peer or enough depth, the status checks cover that
+        let Some(tx) = wallet.iter().find(|t| t.hash == txid && t.blockheight == 0) else {
+            continue;
+        };
+        let feerate = match chan.inflight.as_ref().and_then(|i| i.last()) {
+            Some(inflight) => inflight
+                .feerate
+                .trim_end_matches("perkw")
+                .parse::<u64>()
+                .ok(),
+            None => tx_feerate(tx, &wallet),
+        };
+        let change = outputs
+            .iter()
+            .find(|o| o.txid == txid)
+            .map(|o| format!("{}:{}", o.txid, o.output));
+        let feerate_text = feerate
+            .map(sat_per_vbyte)
+            .unwrap_or_else(|| "unknown".to_string());
+        let target_text = target
+            .map(sat_per_vbyte)
+            .unwrap_or_else(|| "unknown".to_string());
+        let command = match target {
+            Some(target) => bump_command(chan, tx, feerate, target, change),
+            None => "CLN has no feerate estimate to bump it with".to_string(),
+        };
+        let message = format!(
+            "Funding transaction {} unconfirmed for {} blocks. Feerate {} sat/vB, CLN \
+            estimates {} sat/vB for opens. {}",
+            txid, blocks, feerate_text, target_text, command
+        );
+        warn!("check_channel: {} {}", chan.peer_id, message);
+        add_finding(
+            findings,
+            FindingCode::UnconfirmedFunding,
+            chan,
+            message,
+            vec![
+                ("txid", txid),
+                ("blocks", blocks.to_string()),
+                ("feerate", feerate_text),
+                ("estimate", target_text),
+                ("command", command),
+            ],
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,139 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+use chrono::Utc;
+use cln_plugin::Plugin;
+use cln_rpc::{
+    primitives::{PublicKey, ShortChannelId},
+    ClnRpc,
+};
+use log::info;
+use serde_json::json;
+
+use crate::{
+    structs::{Config, IgnoreEntry, IgnoreTarget, PluginState},
+    util::{
+        datastore_load,
+        datastore_save,
+        duration_param,
+        get_param,
+        make_rpc_path,
+        timestamp_after,
+    },
+};
+
+const DATASTORE_KEY: &str = "ignores";
+
+pub async fn load_ignores(rpc: &mut ClnRpc) -> Result<Vec<IgnoreEntry>, Error> {
+    match datastore_load(rpc, DATASTORE_KEY).await? {
+        Some(ignores) => Ok(serde_json::from_str(&ignores)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+async fn save_ignores(plugin: &Plugin<PluginState>, ignores: &[IgnoreEntry]) -> Result<(), Error> {
+    let mut rpc = ClnRpc::new(make_rpc_path(plugin)).await?;
+    datastore_save(&mut rpc, DATASTORE_KEY, serde_json::to_string(ignores)?).await
+}
+
+/// Check if a finding for `peer_id` or `scid` should be dropped because the user
+/// ignored the peer or channel via options or `vitality-ignore`
+pub fn is_ignored(
+    config: &Config,
+    ignores: &[IgnoreEntry],
+    peer_id: &PublicKey,
+    scid: Option<ShortChannelId>,
+) -> bool {
+    if config.ignore_peers.contains(peer_id) {
+        return true;
+    }
+    if let Some(scid) = scid {
+        if config.ignore_channels.contains(&scid) {
+            return true;
+        }
+    }
+    let now = Utc::now().timestamp();
+    ignores
+        .iter()
+        .filter(|i| i.until.is_none_or(|until| until > now))
+        .any(|i| match i.target {
+            IgnoreTarget::Peer(p) => p == *peer_id,
+            IgnoreTarget::Channel(c) => Some(c) == scid,
+        })
+}
+
+pub async fn ignore(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let action = get_param(&args, 0, "action")
+        .and_then(|a| a.as_str())
+        .unwrap_or("list");
+    let target = get_param(&args, 1, "target")
+        .map(|t| {
+            t.as_str()
+                .ok_or_else(|| anyhow!("target must be a string"))
+                .and_then(IgnoreTarget::from_str)
+        })
+        .transpose()?;
+    let duration = get_param(&args, 2, "duration")
+        .map(duration_param)
+        .transpose()?;
+
+    let now = Utc::now().timestamp();
+    let mut ignores = plugin.state().ignores.lock().clone();
+    ignores.retain(|i| i.until.is_none_or(|until| until > now));
+
+    match action {
+        "add" => {
+            let target = target.ok_or_else(|| anyhow!("Missing target to ignore"))?;
+            ignores.retain(|i| i.target != target);
+            let until = duration.map(|d| timestamp_after(now, d)).transpose()?;
+            ignores.push(IgnoreEntry { target, until });
+            info!("Ignoring {} until {:?}", target, until);
+        }
+        "remove" => {
+            let target = target.ok_or_else(|| anyhow!("Missing target to remove"))?;
+            let len = ignores.len();
+            ignores.retain(|i| i.target != target);
+            if ignores.len() == len {
+                return Err(anyhow!("{} is not ignored via vitality-ignore", target));
+            }
+            info!("No longer ignoring {}", target);
+        }
+        "list" => (),
+        _ => {
+            return Err(anyhow!(
+                "Unknown action: {}. Use add, remove or list",
+                action
+            ))
+        }
+    }
+
+    if action != "list" {
+        save_ignores(&plugin, &ignores).await?;
+    }
+    *plugin.state().ignores.lock() = ignores.clone();
+
+    let config = plugin.state().config.lock().clone();
+    let mut result = Vec::new();
+    for peer in &config.ignore_peers {
+        result.push(json!({"target": peer.to_string(), "type": "peer", "source": "config"}));
+    }
+    for scid in &config.ignore_channels {
+        result.push(json!({"target": scid.to_string(), "type": "channel", "source": "config"}));
+    }
+    for entry in &ignores {
+        let kind = match entry.target {
+            IgnoreTarget::Peer(_) => "peer",
+            IgnoreTarget::Channel(_) => "channel",
+        };
+        result.push(json!({
+            "target": entry.target.to_string(),
+            "type": kind,
+            "source": "rpc",
+            "until": entry.until,
+        }));
+    }
+    Ok(json!({"ignores": result}))
+}
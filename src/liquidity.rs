@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+
+use chrono::Utc;
+use cln_rpc::{
+    model::responses::ListpeerchannelsChannels,
+    primitives::{ChannelState, PublicKey},
+};
+use log::warn;
+use parking_lot::Mutex;
+
+use crate::{
+    channelwatch::add_finding,
+    ignore::is_ignored,
+    structs::{Config, Finding, FindingCode, IgnoreEntry},
+};
+
+/// Since when channels are depleted by channel id, `true` if the outbound side is depleted
+pub type Depletions = HashMap<String, (bool, i64)>;
+
+/// Usable outbound and inbound liquidity of `chan` in msat, without the reserves
+fn usable_liquidity(chan: &ListpeerchannelsChannels) -> Option<(u64, u64)> {
+    let total = chan.total_msat?.msat();
+    let to_us = chan.to_us_msat?.msat();
+    let our_reserve = chan.our_reserve_msat.map(|r| r.msat()).unwrap_or_default();
+    let their_reserve = chan
+        .their_reserve_msat
+        .map(|r| r.msat())
+        .unwrap_or_default();
+    Some((
+        to_us.saturating_sub(our_reserve),
+        total.saturating_sub(to_us).saturating_sub(their_reserve),
+    ))
+}
+
+fn direction(outbound: bool) -> &'static str {
+    if outbound {
+        "outbound"
+    } else {
+        "inbound"
+    }
+}
+
+/// The side of a channel or node with at most `min_ratio` percent of the usable
+/// liquidity and its share in percent
+fn depleted_side(outbound: u64, inbound: u64, min_ratio: u32) -> Option<(bool, u64)> {
+    let usable = outbound + inbound;
+    if usable == 0 {
+        return None;
+    }
+    let (side, share) = if outbound <= inbound {
+        (true, outbound)
+    } else {
+        (false, inbound)
+    };
+    if share * 100 <= u64::from(min_ratio) * usable {
+        Some((side, share * 100 / usable))
+    } else {
+        None
+    }
+}
+
+/// Remember since when channels are depleted in one direction and flag channels
+/// that stay depleted for longer than `vitality-depleted-after`
+pub fn check_depleted(
+    channels: &[ListpeerchannelsChannels],
+    config: &Config,
+    depletions: &Mutex<Depletions>,
+    findings: &mut Vec<Finding>,
+) {
+    let now = Utc::now().timestamp();
+    let mut depletions = depletions.lock();
+    for chan in channels {
+        let Some(channel_id) = chan.channel_id.map(|c| c.to_string()) else {
+            continue;
+        };
+        let depleted = if chan.state == ChannelState::CHANNELD_NORMAL {
+            usable_liquidity(chan).and_then(|(outbound, inbound)| {
+                depleted_side(outbound, inbound, config.channel_min_ratio)
+                    .map(|(side, share)| (side, share, outbound, inbound))
+            })
+        } else {
+            None
+        };
+        let Some((outbound_side, share, outbound, inbound)) = depleted else {
+            depletions.remove(&channel_id);
+            continue;
+        };
+        let since = depletions
+            .entry(channel_id)
+            .and_modify(|(side, since)| {
+                if *side != outbound_side {
+                    *side = outbound_side;
+                    *since = now;
+                }
+            })
+            .or_insert((outbound_side, now))
+            .1;
+        let age = now - since;
+        if age < config.depleted_after as i64 {
+            continue;
+        }
+        let scid = chan
+            .short_channel_id
+            .map(|s| s.to_string())
+            .unwrap_or_default();
+        let side_sat = (if outbound_side { outbound } else { inbound }) / 1_000;
+        let usable_sat = (outbound + inbound) / 1_000;
+        warn!(
+            "check_channel: Found peer {} with channel {} without {} liquidity for {}h",
+            chan.peer_id,
+            scid,
+            direction(outbound_side),
+            age / 3_600
+        );
+        add_finding(
+            findings,
+            FindingCode::DepletedChannel,
+            chan,
+            format!(
+                "Found channel {} without {} liquidity for {}h: {} of {} usable sats ({}%)",
+                scid,
+                direction(outbound_side),
+                age / 3_600,
+                side_sat,
+                usable_sat,
+                share
+            ),
+            vec![
+                ("direction", direction(outbound_side).to_string()),
+                ("ratio", share.to_string()),
+                ("sats", side_sat.to_string()),
+                ("hours", (age / 3_600).to_string()),
+            ],
+        );
+    }
+}
+
+/// Flag the node if less than `vitality-node-min-ratio` percent of the usable
+/// liquidity of all its normal channels is on one side. The finding is raised
+/// for our own node id.
+pub fn check_node_balance(
+    channels: &[ListpeerchannelsChannels],
+    config: &Config,
+    ignores: &[IgnoreEntry],
+    my_pubkey: PublicKey,
+    findings: &mut Vec<Finding>,
+) {
+    let (outbound, inbound) = channels
+        .iter()
+        .filter(|c| {
+            c.state == ChannelState::CHANNELD_NORMAL
+                && !is_ignored(config, ignores, &c.peer_id, c.short_channel_id)
+        })
+        .filter_map(usable_liquidity)
+        .fold((0, 0), |(o, i), (co, ci)| (o + co, i + ci));
+    let Some((outbound_side, share)) = depleted_side(outbound, inbound, config.node_min_ratio)
+    else {
+        return;
+    };
+    let side_sat = (if outbound_side { outbound } else { inbound }) / 1_000;
+    let usable_sat = (outbound + inbound) / 1_000;
+    warn!(
+        "check_channel: Only {}% of the usable liquidity of the node is {}",
+        share,
+        direction(outbound_side)
+    );
+    findings.push(Finding {
+        code: FindingCode::NodeImbalance,
+        peer_id: my_pubkey,
+        scid: None,
+        message: format!(
+            "Only {}% of the usable liquidity of the node is {}: {} of {} sats",
+            share,
+            direction(outbound_side),
+            side_sat,
+            usable_sat
+        ),
+        vars: vec![
+            ("direction", direction(outbound_side).to_string()),
+            ("ratio", share.to_string()),
+            ("sats", side_sat.to_string()),
+        ],
+    });
+}
@@ -3,7 +3,6 @@ use std::path::Path;
 use cln_rpc::{ClnRpc, model::requests::GetinfoRequest};
 use config::setconfig_callback;
 use mimalloc::MiMalloc;
-use serde_json::json;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
@@ -13,26 +12,52 @@ extern crate serde_json;
 use anyhow::anyhow;
 use cln_plugin::{
     Builder,
-    Error,
-    Plugin,
     options::{BooleanConfigOption, ConfigOption, IntegerConfigOption, StringConfigOption},
 };
-use log::{info, warn};
+use log::info;
 use structs::{PLUGIN_NAME, PluginState};
 
-use crate::{
-    config::get_startup_options,
-    util::{send_mail, send_telegram},
-};
+use crate::config::get_startup_options;
 
 mod amboss;
 mod channelwatch;
+mod closures;
 mod config;
+mod escalation;
+mod events;
+mod findings;
+mod funding;
+mod ignore;
+mod liquidity;
+mod maintenance;
+mod mute;
+mod notify;
+mod onchain;
+mod outbox;
+mod peers;
+mod ratelimit;
+mod report;
+mod routing;
+mod schedule;
 mod structs;
+mod tasks;
+mod templates;
+mod uptime;
 mod util;
 
 const OPT_AMBOSS: &str = "vitality-amboss";
 const OPT_EXPIRING_HTLCS: &str = "vitality-expiring-htlcs";
+const OPT_EXPIRING_HTLCS_OUT: &str = "vitality-expiring-htlcs-out";
+const OPT_HTLC_FORCE_CLOSE: &str = "vitality-htlc-force-close";
+const OPT_STUCK_CLOSE_BLOCKS: &str = "vitality-stuck-close-blocks";
+const OPT_STUCK_CLOSE_AFTER: &str = "vitality-stuck-close-after";
+const OPT_FUNDING_UNCONFIRMED_BLOCKS: &str = "vitality-funding-unconfirmed-blocks";
+const OPT_STUCK_HTLC_AFTER: &str = "vitality-stuck-htlc-after";
+const OPT_CHANNEL_MIN_RATIO: &str = "vitality-channel-min-ratio";
+const OPT_DEPLETED_AFTER: &str = "vitality-depleted-after";
+const OPT_NODE_MIN_RATIO: &str = "vitality-node-min-ratio";
+const OPT_OFFLINE_THRESHOLDS: &str = "vitality-offline-thresholds";
+const OPT_FLAP_LIMIT: &str = "vitality-flap-limit";
 const OPT_WATCH_CHANNELS: &str = "vitality-watch-channels";
 const OPT_WATCH_GOSSIP: &str = "vitality-watch-gossip";
 const OPT_TELEGRAM_TOKEN: &str = "vitality-telegram-token";
@@ -43,6 +68,23 @@ const OPT_SMTP_SERVER: &str = "vitality-smtp-server";
 const OPT_SMTP_PORT: &str = "vitality-smtp-port";
 const OPT_EMAIL_FROM: &str = "vitality-email-from";
 const OPT_EMAIL_TO: &str = "vitality-email-to";
+const OPT_IGNORE_PEERS: &str = "vitality-ignore-peers";
+const OPT_IGNORE_CHANNELS: &str = "vitality-ignore-channels";
+const OPT_MAINTENANCE_WINDOWS: &str = "vitality-maintenance-windows";
+const OPT_QUIET_HOURS: &str = "vitality-quiet-hours";
+const OPT_TIMEZONE: &str = "vitality-timezone";
+const OPT_REPORT_DAILY: &str = "vitality-report-daily";
+const OPT_REPORT_WEEKLY: &str = "vitality-report-weekly";
+const OPT_OUTBOX_FALLBACK_AFTER: &str = "vitality-outbox-fallback-after";
+const OPT_MAIL_RATE_LIMIT: &str = "vitality-mail-rate-limit";
+const OPT_TELEGRAM_RATE_LIMIT: &str = "vitality-telegram-rate-limit";
+const OPT_TEMPLATES_DIR: &str = "vitality-templates-dir";
+const OPT_LABEL: &str = "vitality-label";
+const OPT_ROUTES: &str = "vitality-routes";
+const OPT_PEER_GROUPS: &str = "vitality-peer-groups";
+const OPT_ESCALATE_AFTER: &str = "vitality-escalate-after";
+const OPT_ESCALATE_TO: &str = "vitality-escalate-to";
+const OPT_TELEGRAM_ACK: &str = "vitality-telegram-ack";
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
@@ -56,6 +98,61 @@ async fn main() -> Result<(), anyhow::Error> {
         "Set block amount to watch for expiry",
     )
     .dynamic();
+    let opt_expiring_htlcs_out: IntegerConfigOption = ConfigOption::new_i64_no_default(
+        OPT_EXPIRING_HTLCS_OUT,
+        "Set block amount to watch for expiry of the upstream htlc of outgoing htlcs",
+    )
+    .dynamic();
+    let opt_htlc_force_close: IntegerConfigOption = ConfigOption::new_i64_no_default(
+        OPT_HTLC_FORCE_CLOSE,
+        "Force-close channels this many blocks before the upstream htlc of an outgoing htlc expires",
+    )
+    .dynamic();
+    let opt_stuck_close_blocks: IntegerConfigOption = ConfigOption::new_i64_no_default(
+        OPT_STUCK_CLOSE_BLOCKS,
+        "Blocks after which an unconfirmed cooperative close is stuck",
+    )
+    .dynamic();
+    let opt_stuck_close_after: StringConfigOption = ConfigOption::new_str_no_default(
+        OPT_STUCK_CLOSE_AFTER,
+        "Duration after which a cooperative close negotiation is stuck, e.g. 24h",
+    )
+    .dynamic();
+    let opt_funding_unconfirmed_blocks: IntegerConfigOption = ConfigOption::new_i64_no_default(
+        OPT_FUNDING_UNCONFIRMED_BLOCKS,
+        "Blocks after which an unconfirmed funding transaction of ours is flagged",
+    )
+    .dynamic();
+    let opt_stuck_htlc_after: StringConfigOption = ConfigOption::new_str_no_default(
+        OPT_STUCK_HTLC_AFTER,
+        "Duration after which a pending htlc is stuck, e.g. 1h",
+    )
+    .dynamic();
+    let opt_channel_min_ratio: IntegerConfigOption = ConfigOption::new_i64_no_default(
+        OPT_CHANNEL_MIN_RATIO,
+        "Percent of usable liquidity on one side at or below which a channel is depleted",
+    )
+    .dynamic();
+    let opt_depleted_after: StringConfigOption = ConfigOption::new_str_no_default(
+        OPT_DEPLETED_AFTER,
+        "Duration after which a depleted channel is flagged, e.g. 12h",
+    )
+    .dynamic();
+    let opt_node_min_ratio: IntegerConfigOption = ConfigOption::new_i64_no_default(
+        OPT_NODE_MIN_RATIO,
+        "Percent of usable liquidity of the node below which it is imbalanced",
+    )
+    .dynamic();
+    let opt_offline_thresholds: StringConfigOption = ConfigOption::new_str_no_default(
+        OPT_OFFLINE_THRESHOLDS,
+        "Comma separated durations after which offline peers are reported, e.g. 1d,7d,30d",
+    )
+    .dynamic();
+    let opt_flap_limit: IntegerConfigOption = ConfigOption::new_i64_no_default(
+        OPT_FLAP_LIMIT,
+        "Number of disconnects per hour above which a peer is flapping",
+    )
+    .dynamic();
     let opt_watch_channels: BooleanConfigOption =
         ConfigOption::new_bool_no_default(OPT_WATCH_CHANNELS, "Switch on/off watch_channels")
             .dynamic();
@@ -77,10 +174,107 @@ async fn main() -> Result<(), anyhow::Error> {
         ConfigOption::new_str_no_default(OPT_EMAIL_FROM, "Set email_from").dynamic();
     let opt_email_to: StringConfigOption =
         ConfigOption::new_str_no_default(OPT_EMAIL_TO, "Set email_to");
+    let opt_ignore_peers: StringConfigOption = ConfigOption::new_str_no_default(
+        OPT_IGNORE_PEERS,
+        "Comma-separated list of peers to not check",
+    )
+    .dynamic();
+    let opt_ignore_channels: StringConfigOption = ConfigOption::new_str_no_default(
+        OPT_IGNORE_CHANNELS,
+        "Comma-separated list of channels to not check",
+    )
+    .dynamic();
+    let opt_maintenance_windows: StringConfigOption = ConfigOption::new_str_no_default(
+        OPT_MAINTENANCE_WINDOWS,
+        "Semicolon-separated list of recurring maintenance windows in cron syntax plus duration",
+    )
+    .dynamic();
+    let opt_quiet_hours: StringConfigOption = ConfigOption::new_str_no_default(
+        OPT_QUIET_HOURS,
+        "Time range HH:MM-HH:MM to only send critical notifications",
+    )
+    .dynamic();
+    let opt_timezone: StringConfigOption = ConfigOption::new_str_no_default(
+        OPT_TIMEZONE,
+        "Timezone for quiet hours, maintenance windows and reports",
+    )
+    .dynamic();
+    let opt_report_daily: StringConfigOption = ConfigOption::new_str_no_default(
+        OPT_REPORT_DAILY,
+        "Time HH:MM to send the daily summary report",
+    )
+    .dynamic();
+    let opt_report_weekly: StringConfigOption = ConfigOption::new_str_no_default(
+        OPT_REPORT_WEEKLY,
+        "Weekday and time, e.g. mon 08:00, to send the weekly summary report",
+    )
+    .dynamic();
+
+    let opt_outbox_fallback_after: IntegerConfigOption = ConfigOption::new_i64_no_default(
+        OPT_OUTBOX_FALLBACK_AFTER,
+        "Failed attempts before a notification is also sent via the other backend, 0 to disable",
+    )
+    .dynamic();
+    let opt_mail_rate_limit: StringConfigOption = ConfigOption::new_str_no_default(
+        OPT_MAIL_RATE_LIMIT,
+        "Comma-separated rate limits for emails, e.g. 10/m,60/h",
+    )
+    .dynamic();
+    let opt_telegram_rate_limit: StringConfigOption = ConfigOption::new_str_no_default(
+        OPT_TELEGRAM_RATE_LIMIT,
+        "Comma-separated rate limits for telegram messages, e.g. 20/m",
+    )
+    .dynamic();
+    let opt_templates_dir: StringConfigOption = ConfigOption::new_str_no_default(
+        OPT_TEMPLATES_DIR,
+        "Directory with message templates, relative to the lightning-dir",
+    )
+    .dynamic();
+    let opt_label: StringConfigOption = ConfigOption::new_str_no_default(
+        OPT_LABEL,
+        "Name of this node in notifications, defaults to the node alias",
+    )
+    .dynamic();
+    let opt_routes: StringConfigOption = ConfigOption::new_str_no_default(
+        OPT_ROUTES,
+        "Semicolon-separated rules routing notifications to backends and recipients",
+    )
+    .dynamic();
+    let opt_peer_groups: StringConfigOption = ConfigOption::new_str_no_default(
+        OPT_PEER_GROUPS,
+        "Semicolon-separated named groups of peers for the routing rules",
+    )
+    .dynamic();
+    let opt_escalate_after: StringConfigOption = ConfigOption::new_str_no_default(
+        OPT_ESCALATE_AFTER,
+        "Duration after which unacknowledged critical findings are escalated, e.g. 15m",
+    )
+    .dynamic();
+    let opt_escalate_to: StringConfigOption = ConfigOption::new_str_no_default(
+        OPT_ESCALATE_TO,
+        "Comma-separated backends and recipients for escalations, e.g. telegram:12345",
+    )
+    .dynamic();
+    let opt_telegram_ack: BooleanConfigOption = ConfigOption::new_bool_no_default(
+        OPT_TELEGRAM_ACK,
+        "Switch on/off acknowledging findings with /ack via the telegram bot",
+    )
+    .dynamic();
 
     let confplugin = match Builder::new(tokio::io::stdin(), tokio::io::stdout())
         .option(opt_amboss)
         .option(opt_expiring_htlcs)
+        .option(opt_expiring_htlcs_out)
+        .option(opt_htlc_force_close)
+        .option(opt_stuck_close_blocks)
+        .option(opt_stuck_close_after)
+        .option(opt_funding_unconfirmed_blocks)
+        .option(opt_stuck_htlc_after)
+        .option(opt_channel_min_ratio)
+        .option(opt_depleted_after)
+        .option(opt_node_min_ratio)
+        .option(opt_offline_thresholds)
+        .option(opt_flap_limit)
         .option(opt_watch_channels)
         .option(opt_watch_gossip)
         .option(opt_telegram_token)
@@ -91,11 +285,96 @@ async fn main() -> Result<(), anyhow::Error> {
         .option(opt_smtp_port)
         .option(opt_email_from)
         .option(opt_email_to)
+        .option(opt_ignore_peers)
+        .option(opt_ignore_channels)
+        .option(opt_maintenance_windows)
+        .option(opt_quiet_hours)
+        .option(opt_timezone)
+        .option(opt_report_daily)
+        .option(opt_report_weekly)
+        .option(opt_outbox_fallback_after)
+        .option(opt_mail_rate_limit)
+        .option(opt_telegram_rate_limit)
+        .option(opt_templates_dir)
+        .option(opt_label)
+        .option(opt_routes)
+        .option(opt_peer_groups)
+        .option(opt_escalate_after)
+        .option(opt_escalate_to)
+        .option(opt_telegram_ack)
         .setconfig_callback(setconfig_callback)
+        .subscribe("connect", events::connect_handler)
+        .subscribe("disconnect", events::disconnect_handler)
+        .subscribe(
+            "channel_state_changed",
+            events::channel_state_changed_handler,
+        )
+        .subscribe("block_added", events::block_added_handler)
+        .subscribe("warning", events::warning_handler)
         .rpcmethod(
             &(PLUGIN_NAME.to_string() + "-testnotifications"),
             "test notifications settings",
-            test_notifications,
+            notify::test_notifications,
+        )
+        .rpcmethod(
+            &(PLUGIN_NAME.to_string() + "-tasks"),
+            "list background tasks and their state",
+            tasks::list_tasks,
+        )
+        .rpcmethod(
+            &(PLUGIN_NAME.to_string() + "-ignore"),
+            "add, remove or list ignored peers and channels",
+            ignore::ignore,
+        )
+        .rpcmethod(
+            &(PLUGIN_NAME.to_string() + "-maintenance"),
+            "start, stop or show maintenance windows",
+            maintenance::maintenance,
+        )
+        .rpcmethod(
+            &(PLUGIN_NAME.to_string() + "-findings"),
+            "list current findings of the channel checks",
+            findings::list_findings,
+        )
+        .rpcmethod(
+            &(PLUGIN_NAME.to_string() + "-ack"),
+            "acknowledge a finding to stop its notifications and escalation",
+            mute::ack,
+        )
+        .rpcmethod(
+            &(PLUGIN_NAME.to_string() + "-mute"),
+            "mute notifications and reconnects for a peer, channel or finding code",
+            mute::mute,
+        )
+        .rpcmethod(
+            &(PLUGIN_NAME.to_string() + "-onchain"),
+            "list funds of closed channels that are not resolved onchain yet and when they are expected back",
+            onchain::onchain,
+        )
+        .rpcmethod(
+            &(PLUGIN_NAME.to_string() + "-offline"),
+            "list peers that are offline for longer than vitality-offline-thresholds with the funds in their channels",
+            peers::offline,
+        )
+        .rpcmethod(
+            &(PLUGIN_NAME.to_string() + "-uptime"),
+            "show the uptime of peers with channels over the last 24 hours, 7 and 30 days",
+            uptime::uptime,
+        )
+        .rpcmethod(
+            &(PLUGIN_NAME.to_string() + "-listmutes"),
+            "list muted peers, channels and finding codes and acknowledged findings",
+            mute::list_mutes,
+        )
+        .rpcmethod(
+            &(PLUGIN_NAME.to_string() + "-report"),
+            "show the daily or weekly summary report",
+            report::report,
+        )
+        .rpcmethod(
+            &(PLUGIN_NAME.to_string() + "-outbox"),
+            "list, retry or drop notifications that could not be delivered yet",
+            outbox::outbox,
         )
         .dynamic()
         .configure()
@@ -115,6 +394,18 @@ async fn main() -> Result<(), anyhow::Error> {
                 Ok(()) => &(),
                 Err(e) => return plugin.disable(format!("{}", e).as_str()).await,
             };
+            *state.ignores.lock() = ignore::load_ignores(&mut rpc).await?;
+            *state.maintenance_until.lock() = maintenance::load_maintenance(&mut rpc).await?;
+            *state.mutes.lock() = mute::load_mutes(&mut rpc).await?;
+            *state.acks.lock() = mute::load_acks(&mut rpc).await?;
+            *state.closures.lock() = closures::load_closures(&mut rpc).await?;
+            *state.offline.lock() = peers::load_offline(&mut rpc).await?;
+            *state.uptime.lock() = uptime::load_uptime(&mut rpc).await?;
+            *state.outbox.lock() = outbox::load_outbox(&mut rpc).await?;
+            *state.quiet_queue.lock() = notify::load_quiet_queue(&mut rpc).await?;
+            if let Some(stats) = report::load_stats(&mut rpc).await? {
+                *state.stats.lock() = stats;
+            }
             info!("read startup options");
             plugin
         }
@@ -122,89 +413,9 @@ async fn main() -> Result<(), anyhow::Error> {
     };
     match confplugin.start(state).await {
         Ok(plugin) => {
-            let config = plugin.state().config.lock().clone();
-
-            if config.amboss {
-                info!("Starting amboss online ping task");
-                let healthclone = plugin.clone();
-                tokio::spawn(async move {
-                    match amboss::amboss_ping_loop(healthclone.clone()).await {
-                        Ok(()) => (),
-                        Err(e) => {
-                            warn!("Error in amboss_ping_loop thread: {}", e);
-                            let config = healthclone.state().config.lock().clone();
-                            let subject = "ALARM: amboss_ping_loop Error".to_string();
-                            let body = e.to_string();
-                            if config.send_mail {
-                                match send_mail(&config, &subject, &body, false).await {
-                                    Ok(_) => (),
-                                    Err(er) => {
-                                        warn!("amboss_ping_loop: Mail failed: {}", er)
-                                    }
-                                };
-                            }
-                            if config.send_telegram {
-                                match send_telegram(&config, &subject, &body).await {
-                                    Ok(_) => (),
-                                    Err(er) => {
-                                        warn!("amboss_ping_loop: Telegram failed: {}", er)
-                                    }
-                                };
-                            }
-                        }
-                    };
-                });
-            }
-
-            if config.expiring_htlcs > 0 || config.watch_channels {
-                let channel_clone = plugin.clone();
-                tokio::spawn(async move {
-                    match channelwatch::check_channels_loop(channel_clone.clone()).await {
-                        Ok(()) => (),
-                        Err(e) => {
-                            warn!("Error in check_channels_loop thread: {}", e);
-                            let config = channel_clone.state().config.lock().clone();
-                            let subject = "ALARM: check_channels_loop Error".to_string();
-                            let body = e.to_string();
-                            if config.send_mail {
-                                match send_mail(&config, &subject, &body, false).await {
-                                    Ok(_) => (),
-                                    Err(er) => {
-                                        warn!("check_channels_loop: Unexpected Error: {}", er)
-                                    }
-                                };
-                            }
-                            if config.send_telegram {
-                                match send_telegram(&config, &subject, &body).await {
-                                    Ok(_) => (),
-                                    Err(er) => {
-                                        warn!("check_channels_loop: Unexpected Error: {}", er)
-                                    }
-                                };
-                            }
-                        }
-                    };
-                });
-            }
-
+            tasks::sync_tasks(&plugin, None);
             plugin.join().await
         }
         _ => Err(anyhow!("Error starting the plugin!")),
     }
 }
-
-async fn test_notifications(
-    plugin: Plugin<PluginState>,
-    _args: serde_json::Value,
-) -> Result<serde_json::Value, Error> {
-    let config = plugin.state().config.lock().clone();
-    let subject = "Test Notification".to_string();
-    let body = "This is a test notification sent from vitality".to_string();
-    if config.send_mail {
-        send_mail(&config, &subject, &body, false).await?;
-    }
-    if config.send_telegram {
-        send_telegram(&config, &subject, &body).await?;
-    }
-    Ok(json!({"format-hint":"simple","result":"success"}))
-}
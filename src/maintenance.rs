@@ -0,0 +1,105 @@
+use anyhow::{anyhow, Error};
+use chrono::Utc;
+use cln_plugin::Plugin;
+use cln_rpc::ClnRpc;
+use log::info;
+use serde_json::json;
+
+use crate::{
+    structs::PluginState,
+    util::{
+        datastore_load,
+        datastore_save,
+        duration_param,
+        get_param,
+        make_rpc_path,
+        timestamp_after,
+    },
+};
+
+const DATASTORE_KEY: &str = "maintenance";
+const DEFAULT_DURATION_S: u64 = 3_600;
+
+pub async fn load_maintenance(rpc: &mut ClnRpc) -> Result<Option<i64>, Error> {
+    match datastore_load(rpc, DATASTORE_KEY).await? {
+        Some(until) if !until.is_empty() => Ok(Some(until.parse()?)),
+        _ => Ok(None),
+    }
+}
+
+/// Returns the end of the current maintenance window, either started via
+/// `vitality-maintenance` or from `vitality-maintenance-windows`
+pub fn maintenance_until(plugin: &Plugin<PluginState>) -> Option<i64> {
+    let config = plugin.state().config.lock();
+    let now = Utc::now().with_timezone(&config.timezone);
+    let manual = (*plugin.state().maintenance_until.lock()).filter(|u| *u > now.timestamp());
+    config
+        .maintenance_windows
+        .iter()
+        .filter_map(|w| w.active_until(&now).map(|end| end.timestamp()))
+        .chain(manual)
+        .max()
+}
+
+pub async fn maintenance(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let action = get_param(&args, 0, "action")
+        .and_then(|a| a.as_str())
+        .unwrap_or("status");
+    let duration = get_param(&args, 1, "duration")
+        .map(duration_param)
+        .transpose()?;
+
+    let now = Utc::now();
+    let manual_until = match action {
+        "start" => {
+            let until = timestamp_after(now.timestamp(), duration.unwrap_or(DEFAULT_DURATION_S))?;
+            info!("Starting maintenance until {}", until);
+            Some(until)
+        }
+        "stop" => {
+            info!("Stopping maintenance");
+            None
+        }
+        "status" => *plugin.state().maintenance_until.lock(),
+        _ => {
+            return Err(anyhow!(
+                "Unknown action: {}. Use start, stop or status",
+                action
+            ))
+        }
+    };
+    if action != "status" {
+        let mut rpc = ClnRpc::new(make_rpc_path(&plugin)).await?;
+        datastore_save(
+            &mut rpc,
+            DATASTORE_KEY,
+            manual_until.map(|u| u.to_string()).unwrap_or_default(),
+        )
+        .await?;
+        *plugin.state().maintenance_until.lock() = manual_until;
+    }
+
+    let config = plugin.state().config.lock().clone();
+    let now = now.with_timezone(&config.timezone);
+    let windows = config
+        .maintenance_windows
+        .iter()
+        .map(|w| {
+            json!({
+                "window": w.to_string(),
+                "active_until": w.active_until(&now).map(|end| end.timestamp()),
+                "next_start": w.next_start(&now).map(|start| start.timestamp()),
+            })
+        })
+        .collect::<Vec<_>>();
+    let until = maintenance_until(&plugin);
+    Ok(json!({
+        "active": until.is_some(),
+        "until": until,
+        "manual_until": manual_until.filter(|u| *u > now.timestamp()),
+        "windows": windows,
+    }))
+}
@@ -0,0 +1,197 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+use chrono::Utc;
+use cln_plugin::Plugin;
+use cln_rpc::ClnRpc;
+use log::{info, warn};
+use serde_json::json;
+
+use crate::{
+    structs::{Finding, FindingKey, MuteEntry, MuteTarget, PluginState, Severity},
+    util::{
+        datastore_load,
+        datastore_save,
+        duration_param,
+        get_param,
+        make_rpc_path,
+        timestamp_after,
+    },
+};
+
+const MUTES_DATASTORE_KEY: &str = "mutes";
+const ACKS_DATASTORE_KEY: &str = "acks";
+
+pub async fn load_mutes(rpc: &mut ClnRpc) -> Result<Vec<MuteEntry>, Error> {
+    match datastore_load(rpc, MUTES_DATASTORE_KEY).await? {
+        Some(mutes) => Ok(serde_json::from_str(&mutes)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+async fn save_mutes(plugin: &Plugin<PluginState>, mutes: &[MuteEntry]) -> Result<(), Error> {
+    let mut rpc = ClnRpc::new(make_rpc_path(plugin)).await?;
+    datastore_save(&mut rpc, MUTES_DATASTORE_KEY, serde_json::to_string(mutes)?).await
+}
+
+pub async fn load_acks(rpc: &mut ClnRpc) -> Result<Vec<FindingKey>, Error> {
+    match datastore_load(rpc, ACKS_DATASTORE_KEY).await? {
+        Some(acks) => Ok(serde_json::from_str(&acks)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+pub async fn save_acks(plugin: &Plugin<PluginState>) {
+    let result = async {
+        let acks = serde_json::to_string(&*plugin.state().acks.lock())?;
+        let mut rpc = ClnRpc::new(make_rpc_path(plugin)).await?;
+        datastore_save(&mut rpc, ACKS_DATASTORE_KEY, acks).await
+    }
+    .await;
+    if let Err(e) = result {
+        warn!("Error saving acknowledged findings: {}", e);
+    }
+}
+
+/// Check if notifications and reconnects for `finding` are muted via `vitality-mute`
+pub fn is_muted(mutes: &[MuteEntry], finding: &Finding) -> bool {
+    let now = Utc::now().timestamp();
+    mutes
+        .iter()
+        .any(|m| m.until > now && m.target.matches(finding))
+}
+
+/// Parse a finding id, `all` or nothing acknowledges all findings
+pub fn parse_id(value: &str) -> Result<Option<u64>, Error> {
+    let value = value.trim();
+    if value.is_empty() || value == "all" {
+        return Ok(None);
+    }
+    value
+        .trim_start_matches('#')
+        .parse()
+        .map(Some)
+        .map_err(|_| anyhow!("{} is not a valid finding id", value))
+}
+
+/// Acknowledge the finding with `id` or all critical findings. Acknowledged findings
+/// are neither notified again nor escalated until they are resolved.
+pub async fn acknowledge(plugin: &Plugin<PluginState>, id: Option<u64>) -> Result<Vec<u64>, Error> {
+    let mut acknowledged = Vec::new();
+    {
+        let mut records = plugin.state().findings.lock();
+        let mut acks = plugin.state().acks.lock();
+        for record in records.values_mut().filter(|r| match id {
+            Some(id) => r.id == id,
+            None => !r.acknowledged && r.finding.code.severity() == Severity::Critical,
+        }) {
+            record.acknowledged = true;
+            acknowledged.push(record.id);
+            if !acks.contains(&record.finding.key()) {
+                acks.push(record.finding.key());
+            }
+        }
+    }
+    if let Some(id) = id {
+        if acknowledged.is_empty() {
+            return Err(anyhow!("No finding with id {}", id));
+        }
+    }
+    acknowledged.sort();
+    info!("Acknowledged findings {:?}", acknowledged);
+    save_acks(plugin).await;
+    Ok(acknowledged)
+}
+
+pub async fn ack(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let id = match get_param(&args, 0, "id") {
+        Some(id) => match id.as_u64() {
+            Some(id) => Some(id),
+            None => parse_id(id.as_str().ok_or_else(|| anyhow!("id must be a number"))?)?,
+        },
+        None => {
+            return Err(anyhow!(
+                "Missing id, use an id from vitality-findings or all"
+            ))
+        }
+    };
+    let acknowledged = acknowledge(&plugin, id).await?;
+    Ok(json!({"acknowledged": acknowledged}))
+}
+
+pub async fn mute(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let target = get_param(&args, 0, "target")
+        .ok_or_else(|| anyhow!("Missing target to mute"))?
+        .as_str()
+        .ok_or_else(|| anyhow!("target must be a string"))
+        .and_then(MuteTarget::from_str)?;
+    let duration = get_param(&args, 1, "duration")
+        .ok_or_else(|| anyhow!("Missing duration, e.g. 2h"))
+        .and_then(duration_param)?;
+
+    let now = Utc::now().timestamp();
+    let mut mutes = plugin.state().mutes.lock().clone();
+    mutes.retain(|m| m.until > now && m.target != target);
+    let until = if duration == 0 {
+        info!("Unmuted {}", target);
+        None
+    } else {
+        let until = timestamp_after(now, duration)?;
+        mutes.push(MuteEntry { target, until });
+        info!("Muted {} until {}", target, until);
+        Some(until)
+    };
+    save_mutes(&plugin, &mutes).await?;
+    *plugin.state().mutes.lock() = mutes;
+
+    Ok(json!({"target": target.to_string(), "until": until}))
+}
+
+pub async fn list_mutes(
+    plugin: Plugin<PluginState>,
+    _args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let now = Utc::now().timestamp();
+    let mutes = plugin
+        .state()
+        .mutes
+        .lock()
+        .iter()
+        .filter(|m| m.until > now)
+        .map(|m| {
+            let kind = match m.target {
+                MuteTarget::Peer(_) => "peer",
+                MuteTarget::Channel(_) => "channel",
+                MuteTarget::Code(_) => "code",
+            };
+            json!({
+                "target": m.target.to_string(),
+                "type": kind,
+                "until": m.until,
+            })
+        })
+        .collect::<Vec<_>>();
+    let records = plugin.state().findings.lock();
+    let acks = plugin
+        .state()
+        .acks
+        .lock()
+        .iter()
+        .map(|key| {
+            let (code, peer_id, scid) = key;
+            json!({
+                "id": records.get(key).map(|r| r.id),
+                "code": code.to_string(),
+                "peer_id": peer_id.to_string(),
+                "short_channel_id": scid.map(|s| s.to_string()),
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(json!({"mutes": mutes, "acknowledged": acks}))
+}
@@ -0,0 +1,295 @@
+use std::{str::FromStr, time::Duration};
+
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Utc};
+use cln_plugin::Plugin;
+use cln_rpc::ClnRpc;
+use log::{info, warn};
+use serde_json::json;
+use tokio::time;
+
+use crate::{
+    maintenance::maintenance_until,
+    outbox::{defer, enqueue, failed_recipients, send_to},
+    ratelimit::acquire,
+    routing::route,
+    structs::{Backend, Config, Message, PluginState, QueuedNotification, Severity, Target},
+    templates::{join, Templates},
+    util::{datastore_load, datastore_save, get_param, make_rpc_path},
+};
+
+const DATASTORE_KEY: &str = "quiet_queue";
+
+pub async fn load_quiet_queue(rpc: &mut ClnRpc) -> Result<Vec<QueuedNotification>, Error> {
+    match datastore_load(rpc, DATASTORE_KEY).await? {
+        Some(queue) => Ok(serde_json::from_str(&queue)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+async fn save_quiet_queue(plugin: &Plugin<PluginState>) {
+    let result = async {
+        let queue = serde_json::to_string(&*plugin.state().quiet_queue.lock())?;
+        let mut rpc = ClnRpc::new(make_rpc_path(plugin)).await?;
+        datastore_save(&mut rpc, DATASTORE_KEY, queue).await
+    }
+    .await;
+    if let Err(e) = result {
+        warn!("Error saving quiet hours queue: {}", e);
+    }
+}
+
+/// Send a notification to the recipients routed by its severity, see [`notify_targets`]
+pub async fn notify(plugin: &Plugin<PluginState>, severity: Severity, message: Message) {
+    let targets = route(&plugin.state().config.lock(), severity, None);
+    notify_targets(plugin, severity, targets, message).await;
+}
+
+/// Send a notification to `targets`, unless we are in a maintenance window. During
+/// quiet hours only critical notifications are sent, the rest is queued and sent
+/// as a digest once the quiet hours are over.
+pub async fn notify_targets(
+    plugin: &Plugin<PluginState>,
+    severity: Severity,
+    targets: Vec<Target>,
+    message: Message,
+) {
+    if let Some(until) = maintenance_until(plugin) {
+        info!(
+            "Maintenance until {}, not sending notification with subject `{}`",
+            until, message.subject
+        );
+        return;
+    }
+    if severity < Severity::Critical && is_quiet(plugin) {
+        info!(
+            "Quiet hours, queueing {} notification with subject `{}`",
+            severity, message.subject
+        );
+        plugin.state().quiet_queue.lock().push(QueuedNotification {
+            severity,
+            message,
+            queued_at: Utc::now().timestamp(),
+            targets,
+        });
+        save_quiet_queue(plugin).await;
+        return;
+    }
+    flush_quiet_queue(plugin).await;
+    send(plugin, severity, &targets, &message).await;
+}
+
+/// Send a critical notification to `targets` even during a maintenance window, for
+/// actions vitality takes on its own that don't wait for the maintenance to end.
+/// Like all critical notifications it is not held back by the rate limits.
+pub async fn notify_urgent(plugin: &Plugin<PluginState>, targets: Vec<Target>, message: Message) {
+    send(plugin, Severity::Critical, &targets, &message).await;
+}
+
+/// Send to every target, failed sends go to the outbox for retries
+async fn send(
+    plugin: &Plugin<PluginState>,
+    severity: Severity,
+    targets: &[Target],
+    message: &Message,
+) {
+    let config = plugin.state().config.lock().clone();
+    let message = &with_identity(&config, message);
+    for target in targets {
+        if let Err(retry_at) = acquire(plugin, target.backend, severity) {
+            defer(plugin, target, severity, message, retry_at).await;
+            continue;
+        }
+        let results = send_to(&config, target, message).await;
+        if let Some((failed, e)) = failed_recipients(target, results) {
+            warn!(
+                "Error sending {} with subject `{}`: {}",
+                target, message.subject, e
+            );
+            enqueue(plugin, &failed, severity, message, &e).await;
+        }
+    }
+}
+
+/// Prefix the subject with the identity of this node
+fn with_identity(config: &Config, message: &Message) -> Message {
+    Message {
+        subject: format!("{} {}", config.identity(), message.subject),
+        ..message.clone()
+    }
+}
+
+fn is_quiet(plugin: &Plugin<PluginState>) -> bool {
+    let config = plugin.state().config.lock();
+    config
+        .quiet_hours
+        .is_some_and(|q| q.contains(Utc::now().with_timezone(&config.timezone).time()))
+}
+
+/// Send everything queued during quiet hours as one digest per set of targets
+async fn flush_quiet_queue(plugin: &Plugin<PluginState>) {
+    if is_quiet(plugin) {
+        return;
+    }
+    let queue = std::mem::take(&mut *plugin.state().quiet_queue.lock());
+    if queue.is_empty() {
+        return;
+    }
+    save_quiet_queue(plugin).await;
+    let mut digests: Vec<(Vec<Target>, Vec<QueuedNotification>)> = Vec::new();
+    for notification in queue {
+        match digests.iter_mut().find(|(t, _)| *t == notification.targets) {
+            Some((_, notifications)) => notifications.push(notification),
+            None => digests.push((notification.targets.clone(), vec![notification])),
+        }
+    }
+    for (targets, notifications) in digests {
+        send_digest(plugin, &targets, &notifications).await;
+    }
+}
+
+async fn send_digest(
+    plugin: &Plugin<PluginState>,
+    targets: &[Target],
+    queue: &[QueuedNotification],
+) {
+    let timezone = plugin.state().config.lock().timezone;
+    let severity = queue
+        .iter()
+        .map(|n| n.severity)
+        .max()
+        .unwrap_or(Severity::Info);
+    info!("Sending digest of {} queued notifications", queue.len());
+    let subject = format!("Quiet hours digest: {} notifications", queue.len());
+    let entries = queue
+        .iter()
+        .map(|n| {
+            let queued_at = DateTime::from_timestamp(n.queued_at, 0)
+                .map(|t| t.with_timezone(&timezone).format("%H:%M").to_string())
+                .unwrap_or_default();
+            let header = format!(
+                "[{} {}] {}",
+                queued_at,
+                n.severity,
+                n.message.subject.trim()
+            );
+            join(
+                &[Message::plain(String::new(), header), n.message.clone()],
+                "\n",
+            )
+        })
+        .collect::<Vec<_>>();
+    let digest = Message {
+        subject,
+        ..join(&entries, "\n\n")
+    };
+    send(plugin, severity, targets, &digest).await;
+}
+
+pub async fn quiet_hours_loop(plugin: Plugin<PluginState>) -> Result<(), Error> {
+    loop {
+        if maintenance_until(&plugin).is_none() {
+            flush_quiet_queue(&plugin).await;
+        }
+        time::sleep(Duration::from_secs(60)).await;
+    }
+}
+
+/// Send a test notification and report the result per backend and recipient.
+/// Ignores maintenance and quiet hours, but reports if they would hold back a
+/// notification of the given severity right now.
+pub async fn test_notifications(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let backend = get_param(&args, 0, "backend")
+        .and_then(|b| b.as_str())
+        .filter(|b| *b != "all")
+        .map(Backend::from_str)
+        .transpose()?;
+    let severity = get_param(&args, 1, "severity")
+        .and_then(|s| s.as_str())
+        .map(Severity::from_str)
+        .transpose()?
+        .unwrap_or(Severity::Warning);
+
+    let config = plugin.state().config.lock().clone();
+    if let Some(backend) = backend {
+        if !backend.configured(&config) {
+            return Err(anyhow!("{} is not configured", backend));
+        }
+    }
+    let routed = route(&config, severity, None);
+    let held = if maintenance_until(&plugin).is_some() {
+        Some("maintenance")
+    } else if severity < Severity::Critical && is_quiet(&plugin) {
+        Some("quiet-hours")
+    } else {
+        None
+    };
+
+    let message = Templates::load(&plugin).await.render(
+        "test",
+        &[("severity", severity.to_string())],
+        None,
+        Message::plain(
+            "Test Notification".to_string(),
+            format!(
+                "This is a test notification sent from vitality with severity {}",
+                severity
+            ),
+        ),
+    );
+    let message = with_identity(&config, &message);
+    let mut backends = Vec::new();
+    let mut sent = 0;
+    let mut failed = 0;
+    for b in Backend::ALL {
+        let configured = b.configured(&config);
+        let routed_target = routed.iter().find(|t| t.backend == b).cloned();
+        let target = match backend {
+            Some(backend) if backend == b => routed_target.or(Some(Target::new(b))),
+            Some(_) => None,
+            None => routed_target,
+        };
+        let results = match target {
+            None => Vec::new(),
+            Some(target) => send_to(&config, &target, &message).await,
+        };
+        let recipients = results
+            .into_iter()
+            .map(|(recipient, result)| {
+                if result.is_ok() {
+                    sent += 1;
+                } else {
+                    failed += 1;
+                }
+                json!({
+                    "recipient": recipient,
+                    "success": result.is_ok(),
+                    "error": result.err().map(|e| e.to_string()),
+                })
+            })
+            .collect::<Vec<_>>();
+        backends.push(json!({
+            "backend": b,
+            "configured": configured,
+            "routed": routed.iter().any(|t| t.backend == b),
+            "recipients": recipients,
+        }));
+    }
+
+    let result = match (sent, failed) {
+        (0, 0) => "nothing sent",
+        (_, 0) => "success",
+        (0, _) => "failure",
+        _ => "partial failure",
+    };
+    Ok(json!({
+        "result": result,
+        "subject": message.subject,
+        "severity": severity,
+        "held": held,
+        "backends": backends,
+    }))
+}
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+
+use anyhow::Error;
+use chrono::{Duration, Utc};
+use cln_plugin::Plugin;
+use cln_rpc::{
+    model::{
+        requests::{GetinfoRequest, ListfundsRequest, ListpeerchannelsRequest},
+        responses::ListpeerchannelsChannels,
+    },
+    primitives::{ChannelState, PublicKey, ShortChannelId},
+    ClnRpc,
+};
+use log::warn;
+use parking_lot::Mutex;
+use serde_json::json;
+
+use crate::{
+    channelwatch::add_finding,
+    structs::{Config, Finding, FindingCode, PluginState},
+    util::{make_rpc_path, BLOCK_INTERVAL_S},
+};
+
+/// Blocks a resolution may be late before we alert, sweeps can take a few blocks
+/// to confirm in busy fee markets
+const RESOLUTION_GRACE_BLOCKS: u32 = 6;
+
+/// Expected block heights of unresolved outputs by channel id and output
+pub type Resolutions = HashMap<(String, String), u32>;
+
+/// An output onchaind still has to resolve, parsed from a status like
+/// `ONCHAIN:2 outputs unresolved: in 143 blocks will spend DELAYED_OUTPUT_TO_US (ab12..:0) using OUR_DELAYED_RETURN_TO_WALLET`
+struct Unresolved {
+    output: String,
+    /// `None` if we wait for the peer, `Some(0)` if our sweep waits for confirmation
+    blocks_left: Option<u32>,
+}
+
+fn parse_unresolved(status: &str) -> Option<Unresolved> {
+    let (_, rest) = status.split_once("outputs unresolved: ")?;
+    let output = |s: &str| s.split(" using ").next().unwrap_or(s).trim().to_string();
+    if let Some(rest) = rest.strip_prefix("in ") {
+        let (blocks, rest) = rest.split_once(" block")?;
+        let (_, spend) = rest.split_once("will spend ")?;
+        return Some(Unresolved {
+            output: output(spend),
+            blocks_left: blocks.trim().parse().ok(),
+        });
+    }
+    if let Some((_, spent)) = rest.split_once("waiting confirmation that we spent ") {
+        return Some(Unresolved {
+            output: output(spent),
+            blocks_left: Some(0),
+        });
+    }
+    Some(Unresolved {
+        output: rest.trim().to_string(),
+        blocks_left: None,
+    })
+}
+
+fn unresolved_outputs(chan: &ListpeerchannelsChannels) -> Vec<(Unresolved, String)> {
+    chan.status
+        .iter()
+        .flatten()
+        .filter_map(|s| parse_unresolved(s).map(|u| (u, s.clone())))
+        .collect()
+}
+
+/// Remember when each unresolved output of channels in `ONCHAIN` is expected to be
+/// resolved and flag outputs that are more than `RESOLUTION_GRACE_BLOCKS` late
+pub fn check_resolutions(
+    channels: &[ListpeerchannelsChannels],
+    resolutions: &Mutex<Resolutions>,
+    findings: &mut Vec<Finding>,
+    current_blockheight: u32,
+) {
+    let mut resolutions = resolutions.lock();
+    for chan in channels {
+        let Some(channel_id) = chan.channel_id.map(|c| c.to_string()) else {
+            continue;
+        };
+        let outputs = if chan.state == ChannelState::ONCHAIN {
+            unresolved_outputs(chan)
+        } else {
+            Vec::new()
+        };
+        resolutions
+            .retain(|(c, o), _| c != &channel_id || outputs.iter().any(|(u, _)| &u.output == o));
+        for (unresolved, status) in outputs {
+            let Some(blocks_left) = unresolved.blocks_left else {
+                continue;
+            };
+            let expected = *resolutions
+                .entry((channel_id.clone(), unresolved.output.clone()))
+                .or_insert(current_blockheight + blocks_left);
+            let overdue = current_blockheight.saturating_sub(expected);
+            if overdue > RESOLUTION_GRACE_BLOCKS {
+                warn!(
+                    "check_channel: Onchain resolution of {} for {} is {} blocks overdue",
+                    unresolved.output, chan.peer_id, overdue
+                );
+                add_finding(
+                    findings,
+                    FindingCode::ResolutionOverdue,
+                    chan,
+                    format!(
+                        "Onchain output {} should have been resolved at block {}, {} blocks ago. \
+                        Status: {}",
+                        unresolved.output, expected, overdue, status
+                    ),
+                    vec![
+                        ("output", unresolved.output),
+                        ("expected_block", expected.to_string()),
+                        ("blocks_overdue", overdue.to_string()),
+                        ("status", status),
+                    ],
+                );
+            }
+        }
+    }
+}
+
+pub struct PendingOutput {
+    pub output: String,
+    pub expected_block: Option<u32>,
+}
+
+/// A closed channel with funds that are not back in our wallet yet
+pub struct OnchainChannel {
+    pub peer_id: PublicKey,
+    pub short_channel_id: Option<ShortChannelId>,
+    pub channel_id: String,
+    pub our_amount_msat: u64,
+    pub outputs: Vec<PendingOutput>,
+}
+impl OnchainChannel {
+    /// Block height at which the last of our outputs is expected back
+    pub fn expected_block(&self) -> Option<u32> {
+        self.outputs.iter().filter_map(|o| o.expected_block).max()
+    }
+}
+
+pub async fn pending_resolutions(
+    plugin: &Plugin<PluginState>,
+    rpc: &mut ClnRpc,
+) -> Result<Vec<OnchainChannel>, Error> {
+    let channels = rpc
+        .call_typed(&ListpeerchannelsRequest {
+            id: None,
+            channel_id: None,
+            short_channel_id: None,
+        })
+        .await?
+        .channels;
+    let funds = rpc
+        .call_typed(&ListfundsRequest { spent: None })
+        .await?
+        .channels;
+    let resolutions = plugin.state().resolutions.lock().clone();
+
+    let mut pending = Vec::new();
+    for chan in channels.iter().filter(|c| c.state == ChannelState::ONCHAIN) {
+        let channel_id = chan.channel_id.map(|c| c.to_string()).unwrap_or_default();
+        let outputs = unresolved_outputs(chan)
+            .into_iter()
+            .map(|(u, _)| PendingOutput {
+                expected_block: resolutions
+                    .get(&(channel_id.clone(), u.output.clone()))
+                    .copied(),
+                output: u.output,
+            })
+            .collect::<Vec<_>>();
+        if outputs.is_empty() {
+            continue;
+        }
+        let our_amount_msat = funds
+            .iter()
+            .find(|f| f.channel_id.to_string() == channel_id)
+            .map(|f| f.our_amount_msat.msat())
+            .unwrap_or_default();
+        pending.push(OnchainChannel {
+            peer_id: chan.peer_id,
+            short_channel_id: chan.short_channel_id,
+            channel_id,
+            our_amount_msat,
+            outputs,
+        });
+    }
+    Ok(pending)
+}
+
+/// Estimated local time of `block`
+pub fn estimate_block_time(config: &Config, block: u32, current_blockheight: u32) -> String {
+    let blocks = i64::from(block.saturating_sub(current_blockheight));
+    (Utc::now() + Duration::seconds(blocks * BLOCK_INTERVAL_S))
+        .with_timezone(&config.timezone)
+        .format("%Y-%m-%d %H:%M %Z")
+        .to_string()
+}
+
+pub async fn onchain(
+    plugin: Plugin<PluginState>,
+    _args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let mut rpc = ClnRpc::new(make_rpc_path(&plugin)).await?;
+    let current_blockheight = rpc.call_typed(&GetinfoRequest {}).await?.blockheight;
+    let config = plugin.state().config.lock().clone();
+    let pending = pending_resolutions(&plugin, &mut rpc).await?;
+
+    let channels = pending
+        .iter()
+        .map(|c| {
+            let outputs = c
+                .outputs
+                .iter()
+                .map(|o| {
+                    json!({
+                        "output": o.output,
+                        "expected_block": o.expected_block,
+                        "blocks_left": o
+                            .expected_block
+                            .map(|b| i64::from(b) - i64::from(current_blockheight)),
+                        "expected_at": o
+                            .expected_block
+                            .map(|b| estimate_block_time(&config, b, current_blockheight)),
+                    })
+                })
+                .collect::<Vec<_>>();
+            json!({
+                "peer_id": c.peer_id.to_string(),
+                "short_channel_id": c.short_channel_id.map(|s| s.to_string()),
+                "channel_id": c.channel_id,
+                "our_amount_msat": c.our_amount_msat,
+                "expected_block": c.expected_block(),
+                "outputs": outputs,
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(json!({
+        "blockheight": current_blockheight,
+        "total_msat": pending.iter().map(|c| c.our_amount_msat).sum::<u64>(),
+        "channels": channels,
+    }))
+}
@@ -0,0 +1,446 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use chrono::Utc;
+use cln_plugin::Plugin;
+use cln_rpc::ClnRpc;
+use log::{info, warn};
+use serde_json::json;
+use tokio::time;
+
+use crate::{
+    ratelimit::acquire,
+    structs::{Backend, Config, Message, OutboxEntry, PluginState, Severity, Target},
+    util::{
+        datastore_load,
+        datastore_save,
+        fits_telegram,
+        get_param,
+        make_rpc_path,
+        send_mail,
+        send_telegram_chats,
+    },
+};
+
+const DATASTORE_KEY: &str = "outbox";
+const RETRY_START_S: i64 = 30;
+const RETRY_MAX_S: i64 = 3_600;
+/// Give up on notifications that could not be delivered for a week
+const MAX_AGE_S: i64 = 7 * 24 * 3_600;
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Limit for combining queued notifications into one message, telegram cuts
+/// messages off at 4000 characters
+const MAX_COMBINED_LEN: usize = 3_500;
+
+pub async fn load_outbox(rpc: &mut ClnRpc) -> Result<Vec<OutboxEntry>, Error> {
+    match datastore_load(rpc, DATASTORE_KEY).await? {
+        Some(outbox) => Ok(serde_json::from_str(&outbox)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+async fn save_outbox(plugin: &Plugin<PluginState>) {
+    let result = async {
+        let outbox = serde_json::to_string(&*plugin.state().outbox.lock())?;
+        let mut rpc = ClnRpc::new(make_rpc_path(plugin)).await?;
+        datastore_save(&mut rpc, DATASTORE_KEY, outbox).await
+    }
+    .await;
+    if let Err(e) = result {
+        warn!("Error saving outbox: {}", e);
+    }
+}
+
+/// Send `message` to each recipient of `target` and return the result per recipient.
+/// Uses the HTML variant for mail and the Markdown variant for telegram if there is one.
+pub async fn send_to(
+    config: &Config,
+    target: &Target,
+    message: &Message,
+) -> Vec<(String, Result<(), Error>)> {
+    let recipients = target.recipients(config);
+    match target.backend {
+        Backend::Mail => {
+            let (body, html) = match &message.html {
+                Some(html) => (html, true),
+                None => (&message.text, false),
+            };
+            let mut results = Vec::new();
+            for recipient in recipients {
+                let result = send_mail(
+                    config,
+                    std::slice::from_ref(&recipient),
+                    &message.subject,
+                    body,
+                    html,
+                )
+                .await;
+                results.push((recipient, result));
+            }
+            results
+        }
+        // A cut Markdown variant may be rejected, the text is safe to cut
+        Backend::Telegram => match &message.markdown {
+            Some(markdown) if fits_telegram(&message.subject, markdown, true) => {
+                send_telegram_chats(config, &recipients, &message.subject, markdown, true).await
+            }
+            _ => {
+                send_telegram_chats(config, &recipients, &message.subject, &message.text, false)
+                    .await
+            }
+        },
+    }
+}
+
+/// The recipients of `target` that failed in `results` and their errors, `None`
+/// if every recipient got the message
+pub fn failed_recipients(
+    target: &Target,
+    results: Vec<(String, Result<(), Error>)>,
+) -> Option<(Target, Error)> {
+    let (recipients, errors): (Vec<_>, Vec<_>) = results
+        .into_iter()
+        .filter_map(|(recipient, result)| {
+            let error = format!("{}: {}", recipient, result.err()?);
+            Some((recipient, error))
+        })
+        .unzip();
+    if recipients.is_empty() {
+        return None;
+    }
+    Some((
+        Target {
+            backend: target.backend,
+            recipients,
+        },
+        anyhow!("Failed to send {} to {}", target.backend, errors.join(", ")),
+    ))
+}
+
+fn retry_delay(attempts: u32) -> i64 {
+    (RETRY_START_S << attempts.saturating_sub(1).min(10)).min(RETRY_MAX_S)
+}
+
+fn push_entry(
+    outbox: &mut Vec<OutboxEntry>,
+    target: &Target,
+    severity: Severity,
+    message: Message,
+    fallback: bool,
+) -> u64 {
+    let id = outbox.iter().map(|e| e.id).max().unwrap_or_default() + 1;
+    let now = Utc::now().timestamp();
+    outbox.push(OutboxEntry {
+        id,
+        backend: target.backend,
+        recipients: target.recipients.clone(),
+        severity,
+        subject: message.subject,
+        body: message.text,
+        html: message.html,
+        markdown: message.markdown,
+        created_at: now,
+        attempts: 0,
+        next_attempt: now,
+        last_error: String::new(),
+        fallback,
+    });
+    id
+}
+
+/// Queue a notification that could not be sent to `target` for retries, `target`
+/// holds only the recipients that failed so the others don't get it twice
+pub async fn enqueue(
+    plugin: &Plugin<PluginState>,
+    target: &Target,
+    severity: Severity,
+    message: &Message,
+    error: &Error,
+) {
+    {
+        let mut outbox = plugin.state().outbox.lock();
+        let id = push_entry(&mut outbox, target, severity, message.clone(), false);
+        if let Some(entry) = outbox.iter_mut().find(|e| e.id == id) {
+            entry.attempts = 1;
+            entry.next_attempt += retry_delay(1);
+            entry.last_error = error.to_string();
+        }
+        info!(
+            "Queued {} notification with subject `{}` in outbox as {}",
+            target, message.subject, id
+        );
+    }
+    save_outbox(plugin).await;
+}
+
+/// Queue a notification that hit the rate limit of its backend until `until`
+pub async fn defer(
+    plugin: &Plugin<PluginState>,
+    target: &Target,
+    severity: Severity,
+    message: &Message,
+    until: i64,
+) {
+    {
+        let mut outbox = plugin.state().outbox.lock();
+        let id = push_entry(&mut outbox, target, severity, message.clone(), false);
+        if let Some(entry) = outbox.iter_mut().find(|e| e.id == id) {
+            entry.next_attempt = until;
+            entry.last_error = "rate limited".to_string();
+        }
+        info!(
+            "Rate limit of {} reached, deferring notification with subject `{}` until {} as {}",
+            target.backend, message.subject, until, id
+        );
+    }
+    save_outbox(plugin).await;
+}
+
+/// One message containing all of `batch`
+fn combine(batch: &[OutboxEntry]) -> Message {
+    if let [entry] = batch {
+        return Message {
+            subject: entry.subject.clone(),
+            text: entry.body.clone(),
+            html: entry.html.clone(),
+            markdown: entry.markdown.clone(),
+        };
+    }
+    let subject = format!("{} queued notifications", batch.len());
+    let body = batch
+        .iter()
+        .map(|e| format!("[{}] {}\n{}", e.severity, e.subject.trim(), e.body))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    Message::plain(subject, body)
+}
+
+/// Schedule the next retry of a failed entry. After `vitality-outbox-fallback-after`
+/// failed attempts a copy is queued for another configured backend, the original
+/// keeps being retried.
+fn record_failure(
+    outbox: &mut Vec<OutboxEntry>,
+    entry: &OutboxEntry,
+    error: &Error,
+    config: &Config,
+) {
+    let now = Utc::now().timestamp();
+    let Some(stored) = outbox.iter_mut().find(|s| s.id == entry.id) else {
+        return;
+    };
+    stored.attempts += 1;
+    stored.next_attempt = now + retry_delay(stored.attempts);
+    stored.last_error = error.to_string();
+
+    if stored.fallback
+        || config.outbox_fallback_after == 0
+        || stored.attempts < config.outbox_fallback_after
+    {
+        return;
+    }
+    let Some(other) = Backend::ALL
+        .into_iter()
+        .find(|b| *b != entry.backend && b.configured(config))
+    else {
+        return;
+    };
+    stored.fallback = true;
+    let body = format!(
+        "Sent via {} because {} failed {} times: {}\n\n{}",
+        other, entry.backend, stored.attempts, error, entry.body
+    );
+    let id = push_entry(
+        outbox,
+        &Target::new(other),
+        entry.severity,
+        Message::plain(entry.subject.clone(), body),
+        true,
+    );
+    info!(
+        "Falling back to {} for notification {} as {}",
+        other, entry.id, id
+    );
+}
+
+/// Retry all notifications in the outbox that are due. Due notifications for the
+/// same backend and recipients are combined into one message to not run into rate
+/// limits again.
+async fn process_outbox(plugin: &Plugin<PluginState>) {
+    let _guard = plugin.state().outbox_lock.lock().await;
+    let now = Utc::now().timestamp();
+    let config = plugin.state().config.lock().clone();
+    let due = plugin
+        .state()
+        .outbox
+        .lock()
+        .iter()
+        .filter(|e| e.next_attempt <= now)
+        .cloned()
+        .collect::<Vec<_>>();
+    if due.is_empty() {
+        return;
+    }
+
+    for entry in due.iter().filter(|e| now - e.created_at > MAX_AGE_S) {
+        warn!(
+            "Giving up on {} notification {} with subject `{}` after {} attempts: {}",
+            entry.backend, entry.id, entry.subject, entry.attempts, entry.last_error
+        );
+        plugin.state().outbox.lock().retain(|e| e.id != entry.id);
+    }
+
+    let mut targets: Vec<Target> = Vec::new();
+    for entry in due.iter().filter(|e| now - e.created_at <= MAX_AGE_S) {
+        if !targets.contains(&entry.target()) {
+            targets.push(entry.target());
+        }
+    }
+    for target in targets {
+        let backend = target.backend;
+        let mut batch = Vec::new();
+        let mut len = 0;
+        for entry in due
+            .iter()
+            .filter(|e| e.target() == target && now - e.created_at <= MAX_AGE_S)
+        {
+            let entry_len = entry.subject.len() + entry.body.len();
+            if !batch.is_empty() && len + entry_len > MAX_COMBINED_LEN {
+                break;
+            }
+            len += entry_len;
+            batch.push(entry.clone());
+        }
+        if batch.is_empty() {
+            continue;
+        }
+
+        let severity = batch
+            .iter()
+            .map(|e| e.severity)
+            .max()
+            .unwrap_or(Severity::Info);
+        let failed = if !backend.configured(&config) {
+            Some((target.clone(), anyhow!("{} is not configured", backend)))
+        } else if let Err(retry_at) = acquire(plugin, backend, severity) {
+            for stored in plugin
+                .state()
+                .outbox
+                .lock()
+                .iter_mut()
+                .filter(|s| batch.iter().any(|b| b.id == s.id))
+            {
+                stored.next_attempt = retry_at;
+            }
+            continue;
+        } else {
+            failed_recipients(&target, send_to(&config, &target, &combine(&batch)).await)
+        };
+
+        let mut outbox = plugin.state().outbox.lock();
+        match failed {
+            None => {
+                info!(
+                    "Delivered {} {} notifications from outbox",
+                    batch.len(),
+                    target
+                );
+                outbox.retain(|e| !batch.iter().any(|b| b.id == e.id));
+            }
+            Some((failed, e)) => {
+                warn!(
+                    "Retry of {} {} notifications failed: {}",
+                    batch.len(),
+                    target,
+                    e
+                );
+                // Only retry the recipients that didn't get it
+                for stored in outbox
+                    .iter_mut()
+                    .filter(|s| batch.iter().any(|b| b.id == s.id))
+                {
+                    stored.recipients = failed.recipients.clone();
+                }
+                for entry in &batch {
+                    record_failure(&mut outbox, entry, &e, &config);
+                }
+            }
+        }
+    }
+    save_outbox(plugin).await;
+}
+
+pub async fn outbox_loop(plugin: Plugin<PluginState>) -> Result<(), Error> {
+    loop {
+        process_outbox(&plugin).await;
+        time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+pub async fn outbox(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let action = get_param(&args, 0, "action")
+        .and_then(|a| a.as_str())
+        .unwrap_or("list");
+    let id = get_param(&args, 1, "id")
+        .map(|i| {
+            i.as_u64()
+                .or_else(|| i.as_str().and_then(|s| s.parse().ok()))
+                .ok_or_else(|| anyhow!("id must be a number"))
+        })
+        .transpose()?;
+
+    {
+        let mut outbox = plugin.state().outbox.lock();
+        if let Some(id) = id {
+            if !outbox.iter().any(|e| e.id == id) {
+                return Err(anyhow!("No notification with id {} in outbox", id));
+            }
+        }
+        let selected = |e: &OutboxEntry| id.is_none_or(|i| i == e.id);
+        match action {
+            "retry" => {
+                let now = Utc::now().timestamp();
+                for entry in outbox.iter_mut().filter(|e| selected(e)) {
+                    entry.next_attempt = now;
+                }
+            }
+            "drop" => outbox.retain(|e| !selected(e)),
+            "list" => (),
+            _ => {
+                return Err(anyhow!(
+                    "Unknown action: {}. Use list, retry or drop",
+                    action
+                ))
+            }
+        }
+    }
+    if action == "retry" {
+        process_outbox(&plugin).await;
+    } else if action == "drop" {
+        save_outbox(&plugin).await;
+    }
+
+    let outbox = plugin
+        .state()
+        .outbox
+        .lock()
+        .iter()
+        .map(|e| {
+            json!({
+                "id": e.id,
+                "backend": e.backend,
+                "recipients": e.recipients,
+                "severity": e.severity,
+                "subject": e.subject,
+                "created_at": e.created_at,
+                "attempts": e.attempts,
+                "next_attempt": e.next_attempt,
+                "last_error": e.last_error,
+                "fallback": e.fallback,
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(json!({"outbox": outbox}))
+}
@@ -0,0 +1,286 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::Error;
+use chrono::Utc;
+use cln_plugin::Plugin;
+use cln_rpc::{
+    model::{
+        requests::{ListnodesRequest, ListpeerchannelsRequest},
+        responses::ListpeerchannelsChannels,
+    },
+    primitives::{ChannelState, PublicKey},
+    ClnRpc,
+};
+use log::{info, warn};
+use parking_lot::Mutex;
+use serde_json::json;
+
+use crate::{
+    ignore::is_ignored,
+    structs::{Config, Finding, FindingCode, OfflinePeer, PluginState},
+    util::{datastore_load, datastore_save, format_duration, make_rpc_path},
+};
+
+const DATASTORE_KEY: &str = "offline";
+/// Window to count disconnects in for `vitality-flap-limit`
+const FLAP_WINDOW_S: i64 = 3_600;
+/// Disconnects this soon after we started a reconnect are our own
+const OWN_DISCONNECT: Duration = Duration::from_secs(60);
+
+pub async fn load_offline(rpc: &mut ClnRpc) -> Result<Vec<OfflinePeer>, Error> {
+    match datastore_load(rpc, DATASTORE_KEY).await? {
+        Some(offline) => Ok(serde_json::from_str(&offline)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+async fn save_offline(plugin: &Plugin<PluginState>) {
+    let result = async {
+        let offline = serde_json::to_string(&*plugin.state().offline.lock())?;
+        let mut rpc = ClnRpc::new(make_rpc_path(plugin)).await?;
+        datastore_save(&mut rpc, DATASTORE_KEY, offline).await
+    }
+    .await;
+    if let Err(e) = result {
+        warn!("Error saving offline peers: {}", e);
+    }
+}
+
+/// Channels in these states need the peer, funds of the others are onchain already
+pub fn needs_peer(chan: &ListpeerchannelsChannels) -> bool {
+    !matches!(
+        chan.state,
+        ChannelState::CLOSINGD_COMPLETE
+            | ChannelState::AWAITING_UNILATERAL
+            | ChannelState::FUNDING_SPEND_SEEN
+            | ChannelState::ONCHAIN
+    )
+}
+
+/// Remember since when peers with channels are disconnected. `channels` are
+/// all our channels, or only those of `peer` for checks of a single peer.
+pub async fn update_offline(
+    plugin: &Plugin<PluginState>,
+    channels: &[ListpeerchannelsChannels],
+    peer: Option<PublicKey>,
+) {
+    let now = Utc::now().timestamp();
+    let changed = {
+        let mut offline = plugin.state().offline.lock();
+        let before = offline.clone();
+        let live = channels
+            .iter()
+            .filter(|c| needs_peer(c))
+            .collect::<Vec<_>>();
+        offline.retain(|o| {
+            let checked = peer.is_none_or(|p| p == o.peer_id);
+            !checked
+                || live
+                    .iter()
+                    .any(|c| c.peer_id == o.peer_id && !c.peer_connected)
+        });
+        for chan in live.iter().filter(|c| !c.peer_connected) {
+            if !offline.iter().any(|o| o.peer_id == chan.peer_id) {
+                info!("check_channel: {} went offline", chan.peer_id);
+                offline.push(OfflinePeer {
+                    peer_id: chan.peer_id,
+                    since: now,
+                });
+            }
+        }
+        *offline != before
+    };
+    if changed {
+        save_offline(plugin).await;
+    }
+}
+
+/// Remember a disconnect of `peer` from the `disconnect` notification, the hourly
+/// checks only see a snapshot and would miss a peer that keeps reconnecting.
+/// Disconnects by our own reconnects don't count.
+pub fn record_disconnect(plugin: &Plugin<PluginState>, peer: PublicKey) {
+    let reconnecting = plugin
+        .state()
+        .last_reconnect
+        .lock()
+        .get(&peer)
+        .is_some_and(|at| at.elapsed() < OWN_DISCONNECT);
+    if reconnecting {
+        return;
+    }
+    let now = Utc::now().timestamp();
+    let mut disconnects = plugin.state().disconnects.lock();
+    disconnects.retain(|_, d| {
+        d.retain(|at| now - at < FLAP_WINDOW_S);
+        !d.is_empty()
+    });
+    disconnects.entry(peer).or_default().push(now);
+}
+
+/// Flag peers with channels that disconnected more than `vitality-flap-limit`
+/// times within the last hour
+pub fn check_flapping(
+    channels: &[ListpeerchannelsChannels],
+    config: &Config,
+    disconnects: &Mutex<HashMap<PublicKey, Vec<i64>>>,
+    findings: &mut Vec<Finding>,
+) {
+    let now = Utc::now().timestamp();
+    let mut peers = channels
+        .iter()
+        .filter(|c| needs_peer(c))
+        .map(|c| c.peer_id)
+        .collect::<Vec<_>>();
+    peers.sort();
+    peers.dedup();
+    let disconnects = disconnects.lock();
+    for peer in peers {
+        let count = disconnects
+            .get(&peer)
+            .map(|d| d.iter().filter(|at| now - **at < FLAP_WINDOW_S).count())
+            .unwrap_or_default();
+        if count <= config.flap_limit as usize {
+            continue;
+        }
+        warn!(
+            "check_channel: Found peer {} that disconnected {} times in the last hour",
+            peer, count
+        );
+        findings.push(Finding {
+            code: FindingCode::FlappingPeer,
+            peer_id: peer,
+            scid: None,
+            message: format!(
+                "Found peer {} that disconnected {} times in the last hour",
+                peer, count
+            ),
+            vars: vec![("disconnects", count.to_string())],
+        });
+    }
+}
+
+/// A peer that is offline for longer than one of `vitality-offline-thresholds`
+pub struct DeadPeer {
+    pub peer_id: PublicKey,
+    pub alias: Option<String>,
+    pub since: i64,
+    /// The longest threshold the peer is offline for
+    pub threshold: u64,
+    pub channels: u64,
+    /// Our balance in its channels
+    pub locked_msat: u64,
+}
+impl DeadPeer {
+    pub fn offline_for(&self) -> u64 {
+        (Utc::now().timestamp() - self.since).max(0) as u64
+    }
+
+    /// One line for the reports, e.g. `02ab.. (alias): offline for 8d 2h (over 7d), 2 channels, 500000 sats locked`
+    pub fn summary(&self) -> String {
+        let name = match &self.alias {
+            Some(alias) => format!("{} ({})", self.peer_id, alias),
+            None => self.peer_id.to_string(),
+        };
+        format!(
+            "{}: offline for {} (over {}), {} channels, {} sats locked",
+            name,
+            format_duration(self.offline_for()),
+            format_duration(self.threshold),
+            self.channels,
+            self.locked_msat / 1_000
+        )
+    }
+}
+
+/// Peers that are offline for longer than the shortest of `vitality-offline-thresholds`,
+/// longest offline first. Ignored peers and channels don't count.
+pub async fn dead_peers(
+    plugin: &Plugin<PluginState>,
+    rpc: &mut ClnRpc,
+) -> Result<Vec<DeadPeer>, Error> {
+    let config = plugin.state().config.lock().clone();
+    let ignores = plugin.state().ignores.lock().clone();
+    let mut offline = plugin.state().offline.lock().clone();
+    offline.sort_by_key(|o| o.since);
+    let now = Utc::now().timestamp();
+
+    let mut dead = Vec::new();
+    for peer in offline {
+        let offline_for = (now - peer.since).max(0) as u64;
+        let Some(threshold) = config
+            .offline_thresholds
+            .iter()
+            .filter(|t| offline_for >= **t)
+            .max()
+            .copied()
+        else {
+            continue;
+        };
+        if is_ignored(&config, &ignores, &peer.peer_id, None) {
+            continue;
+        }
+        let channels = rpc
+            .call_typed(&ListpeerchannelsRequest {
+                id: Some(peer.peer_id),
+                channel_id: None,
+                short_channel_id: None,
+            })
+            .await?
+            .channels
+            .into_iter()
+            .filter(|c| {
+                needs_peer(c) && !is_ignored(&config, &ignores, &c.peer_id, c.short_channel_id)
+            })
+            .collect::<Vec<_>>();
+        if channels.is_empty() {
+            continue;
+        }
+        let alias = rpc
+            .call_typed(&ListnodesRequest {
+                id: Some(peer.peer_id),
+            })
+            .await
+            .ok()
+            .and_then(|n| n.nodes.into_iter().next())
+            .and_then(|n| n.alias);
+        dead.push(DeadPeer {
+            peer_id: peer.peer_id,
+            alias,
+            since: peer.since,
+            threshold,
+            channels: channels.len() as u64,
+            locked_msat: channels
+                .iter()
+                .filter_map(|c| c.to_us_msat)
+                .map(|a| a.msat())
+                .sum(),
+        });
+    }
+    Ok(dead)
+}
+
+pub async fn offline(
+    plugin: Plugin<PluginState>,
+    _args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let mut rpc = ClnRpc::new(make_rpc_path(&plugin)).await?;
+    let dead = dead_peers(&plugin, &mut rpc).await?;
+    let peers = dead
+        .iter()
+        .map(|d| {
+            json!({
+                "peer_id": d.peer_id.to_string(),
+                "alias": d.alias,
+                "since": d.since,
+                "offline_for": format_duration(d.offline_for()),
+                "threshold": format_duration(d.threshold),
+                "channels": d.channels,
+                "locked_msat": d.locked_msat,
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(json!({
+        "total_locked_msat": dead.iter().map(|d| d.locked_msat).sum::<u64>(),
+        "peers": peers,
+    }))
+}
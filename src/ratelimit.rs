@@ -0,0 +1,95 @@
+use anyhow::{anyhow, Error};
+use chrono::Utc;
+use cln_plugin::Plugin;
+
+use crate::{
+    structs::{Backend, PluginState, Severity},
+    util::parse_duration,
+};
+
+/// At most `count` messages per `window_s` seconds
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    count: usize,
+    window_s: i64,
+}
+impl RateLimit {
+    pub fn new(count: usize, window_s: i64) -> RateLimit {
+        RateLimit { count, window_s }
+    }
+
+    /// Parse `<count>/<duration>`, e.g. `20/m` or `100/1h`
+    pub fn parse(spec: &str) -> Result<RateLimit, Error> {
+        let (count, window) = spec
+            .trim()
+            .split_once('/')
+            .ok_or_else(|| anyhow!("{} is not a valid rate limit, use e.g. 20/m", spec))?;
+        let count = count
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .filter(|c| *c > 0)
+            .ok_or_else(|| anyhow!("{} is not a valid message count", count))?;
+        let window = window.trim();
+        let window_s = if window.starts_with(|c: char| c.is_ascii_digit()) {
+            parse_duration(window)?
+        } else {
+            parse_duration(&format!("1{}", window))?
+        };
+        if window_s == 0 {
+            return Err(anyhow!("{} has a time window of zero", spec));
+        }
+        Ok(RateLimit {
+            count,
+            window_s: window_s as i64,
+        })
+    }
+}
+
+/// Parse a comma-separated list of rate limits
+pub fn parse_rate_limits(value: &str) -> Result<Vec<RateLimit>, Error> {
+    value
+        .split(',')
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty())
+        .map(RateLimit::parse)
+        .collect()
+}
+
+/// Take a slot for sending a message via `backend`. If the rate limits of the
+/// backend are exhausted returns the time when the next slot becomes free.
+/// Critical messages always get a slot, but count towards the limits.
+pub fn acquire(
+    plugin: &Plugin<PluginState>,
+    backend: Backend,
+    severity: Severity,
+) -> Result<(), i64> {
+    let limits = backend.rate_limits(&plugin.state().config.lock());
+    let now = Utc::now().timestamp();
+    let mut sent = plugin.state().sent.lock();
+    let sent = sent.entry(backend).or_default();
+
+    let max_window = limits.iter().map(|l| l.window_s).max().unwrap_or_default();
+    sent.retain(|t| now - t < max_window);
+
+    let retry_at = limits
+        .iter()
+        .filter_map(|limit| {
+            let in_window = sent
+                .iter()
+                .filter(|t| now - *t < limit.window_s)
+                .collect::<Vec<_>>();
+            if in_window.len() < limit.count {
+                return None;
+            }
+            Some(in_window[in_window.len() - limit.count] + limit.window_s)
+        })
+        .max();
+    match retry_at {
+        Some(retry_at) if severity < Severity::Critical => Err(retry_at),
+        _ => {
+            sent.push(now);
+            Ok(())
+        }
+    }
+}
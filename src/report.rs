@@ -0,0 +1,380 @@
+use std::{fmt, time::Duration};
+
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Datelike, DurationRound, NaiveTime, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+use cln_plugin::Plugin;
+use cln_rpc::{
+    model::requests::{GetinfoRequest, ListpeerchannelsRequest},
+    primitives::ChannelState,
+    ClnRpc,
+};
+use log::{info, warn};
+use serde_json::json;
+use tokio::time::{self, Instant};
+
+use crate::{
+    notify::notify,
+    onchain::{estimate_block_time, pending_resolutions},
+    peers::dead_peers,
+    structs::{Message, PeriodStats, PluginState, Severity, Stats},
+    templates::Templates,
+    uptime::report_lines,
+    util::{datastore_load, datastore_save, get_param, make_rpc_path},
+};
+
+const DATASTORE_KEY: &str = "stats";
+const SAVE_INTERVAL: Duration = Duration::from_secs(600);
+/// Limit for catching up on minutes missed while the loop was busy
+const MAX_CATCHUP_MINUTES: i32 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Period {
+    Daily,
+    Weekly,
+}
+impl fmt::Display for Period {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Period::Daily => write!(f, "daily"),
+            Period::Weekly => write!(f, "weekly"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    channels_normal: u64,
+    channels_opening: u64,
+    channels_closing: u64,
+    disconnected: u64,
+    findings_critical: u64,
+    findings_warning: u64,
+    findings_info: u64,
+    onchain_channels: u64,
+    onchain_pending_sat: u64,
+    /// Block and estimated time at which the last pending onchain funds are expected back
+    onchain_expected: String,
+    offline_peers: u64,
+    offline_locked_sat: u64,
+    /// One line per peer that is offline for longer than `vitality-offline-thresholds`
+    offline_list: Vec<String>,
+    /// Uptime in the last 24h, 7d and 30d of the peers with the lowest uptime
+    uptime_list: Vec<String>,
+}
+
+pub async fn load_stats(rpc: &mut ClnRpc) -> Result<Option<Stats>, Error> {
+    match datastore_load(rpc, DATASTORE_KEY).await? {
+        Some(stats) => Ok(Some(serde_json::from_str(&stats)?)),
+        None => Ok(None),
+    }
+}
+
+async fn save_stats(plugin: &Plugin<PluginState>) -> Result<(), Error> {
+    let stats = serde_json::to_string(&*plugin.state().stats.lock())?;
+    let mut rpc = ClnRpc::new(make_rpc_path(plugin)).await?;
+    datastore_save(&mut rpc, DATASTORE_KEY, stats).await
+}
+
+fn period_stats(plugin: &Plugin<PluginState>, period: Period) -> PeriodStats {
+    let stats = plugin.state().stats.lock();
+    match period {
+        Period::Daily => stats.daily.clone(),
+        Period::Weekly => stats.weekly.clone(),
+    }
+}
+
+async fn get_health(plugin: &Plugin<PluginState>) -> Result<Health, Error> {
+    let mut rpc = ClnRpc::new(make_rpc_path(plugin)).await?;
+    let channels = rpc
+        .call_typed(&ListpeerchannelsRequest {
+            id: None,
+            channel_id: None,
+            short_channel_id: None,
+        })
+        .await?
+        .channels;
+
+    let mut health = Health::default();
+    for channel in &channels {
+        match channel.state {
+            ChannelState::CHANNELD_NORMAL | ChannelState::CHANNELD_AWAITING_SPLICE => {
+                health.channels_normal += 1;
+                if !channel.peer_connected {
+                    health.disconnected += 1;
+                }
+            }
+            ChannelState::OPENINGD
+            | ChannelState::CHANNELD_AWAITING_LOCKIN
+            | ChannelState::DUALOPEND_OPEN_INIT
+            | ChannelState::DUALOPEND_AWAITING_LOCKIN
+            | ChannelState::DUALOPEND_OPEN_COMMITTED
+            | ChannelState::DUALOPEND_OPEN_COMMIT_READY => health.channels_opening += 1,
+            ChannelState::CHANNELD_SHUTTING_DOWN
+            | ChannelState::CLOSINGD_SIGEXCHANGE
+            | ChannelState::CLOSINGD_COMPLETE
+            | ChannelState::AWAITING_UNILATERAL
+            | ChannelState::FUNDING_SPEND_SEEN
+            | ChannelState::ONCHAIN => health.channels_closing += 1,
+        }
+    }
+    for record in plugin.state().findings.lock().values() {
+        match record.finding.code.severity() {
+            Severity::Critical => health.findings_critical += 1,
+            Severity::Warning => health.findings_warning += 1,
+            Severity::Info => health.findings_info += 1,
+        }
+    }
+
+    let current_blockheight = rpc.call_typed(&GetinfoRequest {}).await?.blockheight;
+    let pending = pending_resolutions(plugin, &mut rpc).await?;
+    health.onchain_channels = pending.len() as u64;
+    health.onchain_pending_sat = pending.iter().map(|c| c.our_amount_msat).sum::<u64>() / 1_000;
+    if let Some(block) = pending.iter().filter_map(|c| c.expected_block()).max() {
+        let config = plugin.state().config.lock().clone();
+        health.onchain_expected = format!(
+            "block {} (~{})",
+            block,
+            estimate_block_time(&config, block, current_blockheight)
+        );
+    }
+    let dead = dead_peers(plugin, &mut rpc).await?;
+    health.offline_peers = dead.len() as u64;
+    health.offline_locked_sat = dead.iter().map(|d| d.locked_msat).sum::<u64>() / 1_000;
+    health.offline_list = dead.iter().map(|d| d.summary()).collect();
+    health.uptime_list = report_lines(plugin, &mut rpc).await;
+    Ok(health)
+}
+
+fn format_since(stats: &PeriodStats, timezone: Tz) -> String {
+    DateTime::from_timestamp(stats.since, 0)
+        .map(|s| {
+            s.with_timezone(&timezone)
+                .format("%Y-%m-%d %H:%M %Z")
+                .to_string()
+        })
+        .unwrap_or_default()
+}
+
+/// Values for the `daily-report` and `weekly-report` templates
+fn report_vars(stats: &PeriodStats, health: &Health, timezone: Tz) -> Vec<(&'static str, String)> {
+    vec![
+        ("since", format_since(stats, timezone)),
+        ("findings_raised", stats.findings_raised.to_string()),
+        ("findings_resolved", stats.findings_resolved.to_string()),
+        (
+            "reconnects_attempted",
+            stats.reconnects_attempted.to_string(),
+        ),
+        ("reconnects_failed", stats.reconnects_failed.to_string()),
+        ("reconnects_fixed", stats.reconnects_fixed.to_string()),
+        ("amboss_pings_ok", stats.amboss_pings_ok.to_string()),
+        ("amboss_pings_failed", stats.amboss_pings_failed.to_string()),
+        ("channels_opened", stats.channels_opened.to_string()),
+        ("channels_closed", stats.channels_closed.to_string()),
+        ("channels_normal", health.channels_normal.to_string()),
+        ("channels_opening", health.channels_opening.to_string()),
+        ("channels_closing", health.channels_closing.to_string()),
+        ("disconnected", health.disconnected.to_string()),
+        ("findings_critical", health.findings_critical.to_string()),
+        ("findings_warning", health.findings_warning.to_string()),
+        ("findings_info", health.findings_info.to_string()),
+        ("onchain_channels", health.onchain_channels.to_string()),
+        (
+            "onchain_pending_sat",
+            health.onchain_pending_sat.to_string(),
+        ),
+        ("onchain_expected", health.onchain_expected.clone()),
+        ("offline_peers", health.offline_peers.to_string()),
+        ("offline_locked_sat", health.offline_locked_sat.to_string()),
+        ("offline_list", health.offline_list.join("\n")),
+        ("uptime_list", health.uptime_list.join("\n")),
+    ]
+}
+
+fn format_report(stats: &PeriodStats, health: &Health, timezone: Tz) -> String {
+    let since = format_since(stats, timezone);
+    let pings = stats.amboss_pings_ok + stats.amboss_pings_failed;
+    let ping_rate = if pings > 0 {
+        format!(
+            "{}/{} successful ({:.1}%)",
+            stats.amboss_pings_ok,
+            pings,
+            stats.amboss_pings_ok as f64 * 100.0 / pings as f64
+        )
+    } else {
+        "none sent".to_string()
+    };
+    let onchain = if health.onchain_channels == 0 {
+        "none pending".to_string()
+    } else if health.onchain_expected.is_empty() {
+        format!(
+            "{} sats in {} closed channels pending",
+            health.onchain_pending_sat, health.onchain_channels
+        )
+    } else {
+        format!(
+            "{} sats in {} closed channels pending, expected back by {}",
+            health.onchain_pending_sat, health.onchain_channels, health.onchain_expected
+        )
+    };
+    let offline = if health.offline_list.is_empty() {
+        "none".to_string()
+    } else {
+        format!(
+            "{} with {} sats locked\n{}",
+            health.offline_peers,
+            health.offline_locked_sat,
+            health.offline_list.join("\n")
+        )
+    };
+    let uptime = if health.uptime_list.is_empty() {
+        "all peers connected all the time".to_string()
+    } else {
+        health.uptime_list.join("\n")
+    };
+    format!(
+        "Summary since {}\n\
+        Findings: {} raised, {} resolved\n\
+        Reconnects: {} attempted, {} failed, {} fixed all findings\n\
+        Amboss pings: {}\n\
+        Channels: {} opened, {} closed\n\n\
+        Current health:\n\
+        Channels: {} normal, {} opening, {} closing\n\
+        Normal channels with disconnected peer: {}\n\
+        Open findings: {} critical, {} warning, {} info\n\
+        Onchain funds: {}\n\
+        Peers offline for long: {}\n\
+        Lowest peer uptime (24h / 7d / 30d):\n{}\n",
+        since,
+        stats.findings_raised,
+        stats.findings_resolved,
+        stats.reconnects_attempted,
+        stats.reconnects_failed,
+        stats.reconnects_fixed,
+        ping_rate,
+        stats.channels_opened,
+        stats.channels_closed,
+        health.channels_normal,
+        health.channels_opening,
+        health.channels_closing,
+        health.disconnected,
+        health.findings_critical,
+        health.findings_warning,
+        health.findings_info,
+        onchain,
+        offline,
+        uptime,
+    )
+}
+
+async fn send_report(plugin: &Plugin<PluginState>, period: Period) -> Result<(), Error> {
+    info!("Sending {} report", period);
+    let stats = period_stats(plugin, period);
+    let health = get_health(plugin).await?;
+    let timezone = plugin.state().config.lock().timezone;
+    let (template, subject) = match period {
+        Period::Daily => ("daily-report", "Daily report".to_string()),
+        Period::Weekly => ("weekly-report", "Weekly report".to_string()),
+    };
+    let body = format_report(&stats, &health, timezone);
+    let message = Templates::load(plugin).await.render(
+        template,
+        &report_vars(&stats, &health, timezone),
+        None,
+        Message::plain(subject, body),
+    );
+    notify(plugin, Severity::Info, message).await;
+
+    {
+        let mut stats = plugin.state().stats.lock();
+        match period {
+            Period::Daily => stats.daily = PeriodStats::new(),
+            Period::Weekly => stats.weekly = PeriodStats::new(),
+        }
+    }
+    save_stats(plugin).await
+}
+
+fn is_due(time: &DateTime<Tz>, weekday: Option<Weekday>, at: NaiveTime) -> bool {
+    time.hour() == at.hour()
+        && time.minute() == at.minute()
+        && weekday.is_none_or(|d| time.weekday() == d)
+}
+
+pub async fn report_loop(plugin: Plugin<PluginState>) -> Result<(), Error> {
+    let minute = chrono::Duration::minutes(1);
+    let mut last_minute = Utc::now().duration_trunc(minute)?;
+    let mut last_save = Instant::now();
+    loop {
+        let now = Utc::now();
+        time::sleep(Duration::from_secs(60 - now.second() as u64)).await;
+
+        let (timezone, daily, weekly) = {
+            let config = plugin.state().config.lock();
+            (config.timezone, config.report_daily, config.report_weekly)
+        };
+        let current_minute = Utc::now().duration_trunc(minute)?;
+        let mut due = Vec::new();
+        let mut check = (current_minute - minute * MAX_CATCHUP_MINUTES).max(last_minute);
+        while check < current_minute {
+            check += minute;
+            let local = check.with_timezone(&timezone);
+            if daily.is_some_and(|at| is_due(&local, None, at)) {
+                due.push(Period::Daily);
+            }
+            if weekly.is_some_and(|(day, at)| is_due(&local, Some(day), at)) {
+                due.push(Period::Weekly);
+            }
+        }
+        last_minute = current_minute;
+
+        for period in due {
+            if let Err(e) = send_report(&plugin, period).await {
+                warn!("Error sending {} report: {}", period, e);
+            }
+        }
+        if last_save.elapsed() >= SAVE_INTERVAL {
+            save_stats(&plugin).await?;
+            last_save = Instant::now();
+        }
+    }
+}
+
+pub async fn report(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let period = match get_param(&args, 0, "period")
+        .and_then(|p| p.as_str())
+        .unwrap_or("daily")
+    {
+        "daily" => Period::Daily,
+        "weekly" => Period::Weekly,
+        p => return Err(anyhow!("Unknown period: {}. Use daily or weekly", p)),
+    };
+    let stats = period_stats(&plugin, period);
+    let health = get_health(&plugin).await?;
+    let timezone = plugin.state().config.lock().timezone;
+    Ok(json!({
+        "period": period.to_string(),
+        "stats": stats,
+        "health": {
+            "channels_normal": health.channels_normal,
+            "channels_opening": health.channels_opening,
+            "channels_closing": health.channels_closing,
+            "disconnected": health.disconnected,
+            "findings_critical": health.findings_critical,
+            "findings_warning": health.findings_warning,
+            "findings_info": health.findings_info,
+            "onchain_channels": health.onchain_channels,
+            "onchain_pending_sat": health.onchain_pending_sat,
+            "onchain_expected": health.onchain_expected,
+            "offline_peers": health.offline_peers,
+            "offline_locked_sat": health.offline_locked_sat,
+            "offline_list": health.offline_list,
+            "uptime_list": health.uptime_list,
+        },
+        "report": format_report(&stats, &health, timezone),
+    }))
+}
@@ -0,0 +1,191 @@
+use std::{collections::HashMap, str::FromStr};
+
+use anyhow::{anyhow, Error};
+use cln_rpc::primitives::PublicKey;
+
+use crate::{
+    config::split_list,
+    structs::{Backend, Config, FindingCode, Severity, Target},
+};
+
+/// What a routing rule matches on
+#[derive(Clone, Debug)]
+enum Condition {
+    Code(FindingCode),
+    Gossip,
+    Severity(Severity),
+    Group(String),
+    Peer(PublicKey),
+}
+impl Condition {
+    fn parse(s: &str) -> Result<Condition, Error> {
+        if let Some(group) = s.strip_prefix("group:") {
+            return Ok(Condition::Group(group.trim().to_string()));
+        }
+        if let Some(peer) = s.strip_prefix("peer:") {
+            return PublicKey::from_str(peer.trim())
+                .map(Condition::Peer)
+                .map_err(|e| anyhow!("{} is not a valid node id: {}", peer, e));
+        }
+        if s == "gossip" {
+            return Ok(Condition::Gossip);
+        }
+        if let Ok(severity) = Severity::from_str(s) {
+            return Ok(Condition::Severity(severity));
+        }
+        FindingCode::from_str(s).map(Condition::Code).map_err(|_| {
+            anyhow!(
+                "{} is not a valid route condition, use a finding code, gossip, a severity, \
+                group:<name> or peer:<node id>",
+                s
+            )
+        })
+    }
+
+    fn matches(
+        &self,
+        config: &Config,
+        severity: Severity,
+        finding: Option<(FindingCode, PublicKey)>,
+    ) -> bool {
+        match self {
+            Condition::Severity(s) => *s == severity,
+            Condition::Code(c) => finding.is_some_and(|(code, _)| code == *c),
+            Condition::Gossip => finding.is_some_and(|(code, _)| code.is_gossip()),
+            Condition::Peer(p) => finding.is_some_and(|(_, peer)| peer == *p),
+            Condition::Group(g) => finding.is_some_and(|(_, peer)| {
+                config
+                    .peer_groups
+                    .get(g)
+                    .is_some_and(|peers| peers.contains(&peer))
+            }),
+        }
+    }
+}
+
+/// Send notifications matching any of `conditions` to `targets`
+#[derive(Clone, Debug)]
+pub struct Route {
+    conditions: Vec<Condition>,
+    targets: Vec<Target>,
+}
+impl Route {
+    /// Parse `<condition>,...=<target>,...`, e.g. `lost-state,expiring-htlc=telegram:12345`
+    fn parse(spec: &str) -> Result<Route, Error> {
+        let (conditions, targets) = spec
+            .split_once('=')
+            .ok_or_else(|| anyhow!("{} is not a valid route, use <conditions>=<targets>", spec))?;
+        let conditions = split_list(conditions)
+            .map(Condition::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        if conditions.is_empty() {
+            return Err(anyhow!("{} has no conditions", spec));
+        }
+        let targets = parse_targets(targets)?;
+        if targets.is_empty() {
+            return Err(anyhow!("{} has no targets", spec));
+        }
+        Ok(Route {
+            conditions,
+            targets,
+        })
+    }
+}
+
+/// Parse a comma-separated list of `mail`, `telegram`, `mail:<address>` or
+/// `telegram:<chat id>`, merging recipients of the same backend
+pub fn parse_targets(value: &str) -> Result<Vec<Target>, Error> {
+    let mut targets: Vec<Target> = Vec::new();
+    for target in split_list(value) {
+        let (backend, recipient) = match target.split_once(':') {
+            Some((backend, recipient)) => (backend, Some(recipient.trim().to_string())),
+            None => (target, None),
+        };
+        let backend = Backend::from_str(backend.trim())?;
+        if !targets.iter().any(|t| t.backend == backend) {
+            targets.push(Target::new(backend));
+        }
+        if let Some(recipient) = recipient.filter(|r| !r.is_empty()) {
+            let target = targets.iter_mut().find(|t| t.backend == backend).unwrap();
+            target.recipients.push(recipient);
+        }
+    }
+    Ok(targets)
+}
+
+/// Parse a semicolon-separated list of routes
+pub fn parse_routes(value: &str) -> Result<Vec<Route>, Error> {
+    value
+        .split(';')
+        .map(|r| r.trim())
+        .filter(|r| !r.is_empty())
+        .map(Route::parse)
+        .collect()
+}
+
+/// Parse a semicolon-separated list of `<name>=<node id>,...`
+pub fn parse_peer_groups(value: &str) -> Result<HashMap<String, Vec<PublicKey>>, Error> {
+    let mut groups = HashMap::new();
+    for group in value.split(';').map(|g| g.trim()).filter(|g| !g.is_empty()) {
+        let (name, peers) = group.split_once('=').ok_or_else(|| {
+            anyhow!(
+                "{} is not a valid peer group, use <name>=<node id>,...",
+                group
+            )
+        })?;
+        let peers = split_list(peers)
+            .map(|p| {
+                PublicKey::from_str(p).map_err(|e| anyhow!("{} is not a valid node id: {}", p, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        groups.insert(name.trim().to_string(), peers);
+    }
+    Ok(groups)
+}
+
+/// Backends and recipients for a notification with `severity`, optionally about
+/// a finding. Targets of all matching routes are merged. If no route matches, or
+/// none of the matching routes has a configured backend, everything goes to the
+/// default recipients of all configured backends.
+pub fn route(
+    config: &Config,
+    severity: Severity,
+    finding: Option<(FindingCode, PublicKey)>,
+) -> Vec<Target> {
+    let mut targets: Vec<Target> = Vec::new();
+    for route in config.routes.iter().filter(|r| {
+        r.conditions
+            .iter()
+            .any(|c| c.matches(config, severity, finding))
+    }) {
+        for target in route
+            .targets
+            .iter()
+            .filter(|t| t.backend.configured(config))
+        {
+            let recipients = target.recipients(config);
+            match targets.iter_mut().find(|t| t.backend == target.backend) {
+                Some(existing) => {
+                    for recipient in recipients {
+                        if !existing.recipients.contains(&recipient) {
+                            existing.recipients.push(recipient);
+                        }
+                    }
+                }
+                None => targets.push(Target {
+                    backend: target.backend,
+                    recipients,
+                }),
+            }
+        }
+    }
+    if targets.is_empty() {
+        return Backend::ALL
+            .into_iter()
+            .filter(|b| b.configured(config))
+            .map(Target::new)
+            .collect();
+    }
+    targets.sort_by_key(|t| t.backend);
+    targets
+}
@@ -0,0 +1,281 @@
+use std::fmt;
+
+use anyhow::{anyhow, Error};
+use chrono::{
+    DateTime,
+    Datelike,
+    Duration,
+    DurationRound,
+    NaiveDate,
+    NaiveTime,
+    Timelike,
+    Weekday,
+};
+
+use crate::util::parse_duration;
+
+/// Limit for searching the next start of a window
+const MAX_LOOKAHEAD_MINUTES: i64 = 60 * 24 * 366;
+
+/// Cron schedule with the five usual fields `minute hour day-of-month month day-of-week`.
+/// Supports `*`, single values, ranges `a-b`, steps `*/n` or `a-b/n` and lists `a,b`.
+#[derive(Clone, Debug)]
+pub struct CronSchedule {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    dom_restricted: bool,
+    dow_restricted: bool,
+}
+impl CronSchedule {
+    pub fn parse(spec: &str) -> Result<CronSchedule, Error> {
+        let fields = spec.split_whitespace().collect::<Vec<&str>>();
+        if fields.len() != 5 {
+            return Err(anyhow!(
+                "{} is not a valid cron schedule, expected 5 fields",
+                spec
+            ));
+        }
+        let mut days_of_week = parse_field(fields[4], 0, 7)?;
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week |= 1;
+        }
+        Ok(CronSchedule {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days_of_month: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            days_of_week,
+            dom_restricted: fields[2] != "*",
+            dow_restricted: fields[4] != "*",
+        })
+    }
+
+    pub fn matches<Tz: chrono::TimeZone>(&self, time: &DateTime<Tz>) -> bool {
+        bit(self.minutes, time.minute())
+            && bit(self.hours, time.hour())
+            && self.matches_day(time.date_naive())
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        if !bit(self.months, date.month()) {
+            return false;
+        }
+        let dom = bit(self.days_of_month, date.day());
+        let dow = bit(self.days_of_week, date.weekday().num_days_from_sunday());
+        if self.dom_restricted && self.dow_restricted {
+            dom || dow
+        } else {
+            dom && dow
+        }
+    }
+
+    /// Matching times of day as hour and minute
+    fn times(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        (0..24).filter(|h| bit(self.hours, *h)).flat_map(move |h| {
+            (0..60)
+                .filter(|m| bit(self.minutes, *m))
+                .map(move |m| (h, m))
+        })
+    }
+}
+
+fn bit(mask: u64, value: u32) -> bool {
+    mask & (1 << value) != 0
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, Error> {
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u32>()
+                    .ok()
+                    .filter(|s| *s > 0)
+                    .ok_or_else(|| anyhow!("{} is not a valid cron step", part))?,
+            ),
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (parse_value(start, min, max)?, parse_value(end, min, max)?)
+        } else {
+            let value = parse_value(range, min, max)?;
+            if part.contains('/') {
+                (value, max)
+            } else {
+                (value, value)
+            }
+        };
+        if start > end {
+            return Err(anyhow!("{} is not a valid cron range", part));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+fn parse_value(value: &str, min: u32, max: u32) -> Result<u32, Error> {
+    value
+        .parse::<u32>()
+        .ok()
+        .filter(|v| *v >= min && *v <= max)
+        .ok_or_else(|| {
+            anyhow!(
+                "{} is not a valid cron value, must be between {} and {}",
+                value,
+                min,
+                max
+            )
+        })
+}
+
+/// Recurring window starting at every match of `schedule` and lasting `duration` seconds
+#[derive(Clone, Debug)]
+pub struct Window {
+    spec: String,
+    schedule: CronSchedule,
+    duration: u64,
+}
+impl Window {
+    /// Parse `<minute> <hour> <day-of-month> <month> <day-of-week> <duration>`
+    pub fn parse(spec: &str) -> Result<Window, Error> {
+        let spec = spec.trim();
+        let (cron, duration) = spec
+            .rsplit_once(char::is_whitespace)
+            .ok_or_else(|| anyhow!("{} is missing a duration", spec))?;
+        let duration = parse_duration(duration)?;
+        if duration == 0 {
+            return Err(anyhow!("{} has a duration of zero", spec));
+        }
+        Ok(Window {
+            spec: spec.to_string(),
+            schedule: CronSchedule::parse(cron)?,
+            duration,
+        })
+    }
+
+    /// Returns the end of the window if `now` is inside of it
+    pub fn active_until<Tz: chrono::TimeZone>(&self, now: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let end = self.last_start(now)? + Duration::seconds(self.duration as i64);
+        (end > *now).then_some(end)
+    }
+
+    /// Latest start at or before `now`, not looking back further than the duration
+    fn last_start<Tz: chrono::TimeZone>(&self, now: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let local = now.naive_local();
+        // One more day for daylight saving time shifts
+        let first_day =
+            (local - Duration::seconds(self.duration as i64) - Duration::days(1)).date();
+        let mut day = local.date();
+        while day >= first_day {
+            if self.schedule.matches_day(day) {
+                // Times skipped by daylight saving time never start a window, times
+                // repeated when it ends can start one twice
+                let start = self
+                    .schedule
+                    .times()
+                    .filter_map(|(h, m)| day.and_hms_opt(h, m, 0))
+                    .flat_map(|time| {
+                        let start = now.timezone().from_local_datetime(&time);
+                        [start.clone().latest(), start.earliest()]
+                    })
+                    .flatten()
+                    .filter(|start| start <= now)
+                    .max();
+                if start.is_some() {
+                    return start;
+                }
+            }
+            day = day.pred_opt()?;
+        }
+        None
+    }
+
+    pub fn next_start<Tz: chrono::TimeZone>(&self, now: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let minute = now.clone().duration_trunc(Duration::minutes(1)).ok()?;
+        (1..=MAX_LOOKAHEAD_MINUTES)
+            .map(|forward| minute.clone() + Duration::minutes(forward))
+            .find(|start| self.schedule.matches(start))
+    }
+}
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.spec)
+    }
+}
+
+/// Daily time range `HH:MM-HH:MM`, may span midnight
+#[derive(Clone, Copy, Debug)]
+pub struct QuietHours {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+impl QuietHours {
+    pub fn parse(spec: &str) -> Result<QuietHours, Error> {
+        let (start, end) = spec
+            .trim()
+            .split_once('-')
+            .ok_or_else(|| anyhow!("{} is not a valid time range, use HH:MM-HH:MM", spec))?;
+        let quiet_hours = QuietHours {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+        };
+        if quiet_hours.start == quiet_hours.end {
+            return Err(anyhow!("{} has the same start and end time", spec));
+        }
+        Ok(quiet_hours)
+    }
+
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start < self.end {
+            time >= self.start && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+impl fmt::Display for QuietHours {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+/// Parse a time of day `HH:MM`
+pub fn parse_time(time: &str) -> Result<NaiveTime, Error> {
+    NaiveTime::parse_from_str(time.trim(), "%H:%M")
+        .map_err(|e| anyhow!("{} is not a valid time: {}", time, e))
+}
+
+/// Parse a weekly time `<weekday> HH:MM`, e.g. `mon 08:00`
+pub fn parse_weekly(spec: &str) -> Result<(Weekday, NaiveTime), Error> {
+    let (day, time) = spec
+        .trim()
+        .split_once(char::is_whitespace)
+        .ok_or_else(|| anyhow!("{} is not a valid weekly time, use <weekday> HH:MM", spec))?;
+    let day = day
+        .parse::<Weekday>()
+        .map_err(|_| anyhow!("{} is not a valid weekday", day))?;
+    Ok((day, parse_time(time)?))
+}
+
+/// Parse a `;`-separated list of windows
+pub fn parse_windows(value: &str) -> Result<Vec<Window>, Error> {
+    value
+        .split(';')
+        .map(|w| w.trim())
+        .filter(|w| !w.is_empty())
+        .map(Window::parse)
+        .collect()
+}
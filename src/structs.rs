@@ -1,6 +1,21 @@
-use std::sync::Arc;
+use std::{collections::HashMap, fmt, str::FromStr, sync::Arc};
 
+use anyhow::{anyhow, Error};
+use chrono::{NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use cln_rpc::primitives::{ChannelState, PublicKey, ShortChannelId};
 use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::{
+    liquidity::Depletions,
+    onchain::Resolutions,
+    ratelimit::RateLimit,
+    routing::Route,
+    schedule::{QuietHours, Window},
+    tasks::{TaskKind, TaskState},
+};
 
 pub const PLUGIN_NAME: &str = "vitality";
 
@@ -8,6 +23,17 @@ pub const PLUGIN_NAME: &str = "vitality";
 pub struct Config {
     pub amboss: bool,
     pub expiring_htlcs: u32,
+    pub expiring_htlcs_out: u32,
+    pub htlc_force_close: u32,
+    pub stuck_close_blocks: u32,
+    pub stuck_close_after: u64,
+    pub funding_unconfirmed_blocks: u32,
+    pub stuck_htlc_after: u64,
+    pub channel_min_ratio: u32,
+    pub depleted_after: u64,
+    pub node_min_ratio: u32,
+    pub offline_thresholds: Vec<u64>,
+    pub flap_limit: u32,
     pub watch_channels: bool,
     pub watch_gossip: bool,
     pub telegram_token: String,
@@ -18,15 +44,57 @@ pub struct Config {
     pub smtp_port: u16,
     pub email_from: String,
     pub email_to: String,
+    pub ignore_peers: Vec<PublicKey>,
+    pub ignore_channels: Vec<ShortChannelId>,
+    pub maintenance_windows: Vec<Window>,
+    pub quiet_hours: Option<QuietHours>,
+    pub timezone: Tz,
+    pub report_daily: Option<NaiveTime>,
+    pub report_weekly: Option<(Weekday, NaiveTime)>,
+    pub outbox_fallback_after: u32,
+    pub mail_rate_limits: Vec<RateLimit>,
+    pub telegram_rate_limits: Vec<RateLimit>,
+    pub templates_dir: String,
+    pub routes: Vec<Route>,
+    pub peer_groups: HashMap<String, Vec<PublicKey>>,
+    pub escalate_after: u64,
+    pub escalate_to: Vec<Target>,
+    pub telegram_ack: bool,
+    pub label: String,
+    pub node_alias: String,
+    pub node_id: String,
+    pub network: String,
     pub send_mail: bool,
     pub send_telegram: bool,
     pub is_at_or_above_24_11: bool,
 }
 impl Config {
+    /// Prefix for notification subjects to tell nodes apart, e.g. `[mynode 02abcdef bitcoin]`
+    pub fn identity(&self) -> String {
+        let name = if self.label.is_empty() {
+            &self.node_alias
+        } else {
+            &self.label
+        };
+        let id = self.node_id.get(..8).unwrap_or(&self.node_id);
+        format!("[{} {} {}]", name, id, self.network)
+    }
+
     pub fn new() -> Config {
         Config {
             amboss: false,
             expiring_htlcs: 0,
+            expiring_htlcs_out: 0,
+            htlc_force_close: 0,
+            stuck_close_blocks: 0,
+            stuck_close_after: 0,
+            funding_unconfirmed_blocks: 0,
+            stuck_htlc_after: 0,
+            channel_min_ratio: 1,
+            depleted_after: 0,
+            node_min_ratio: 0,
+            offline_thresholds: vec![86_400, 604_800, 2_592_000],
+            flap_limit: 0,
             watch_channels: true,
             watch_gossip: false,
             telegram_token: String::new(),
@@ -37,6 +105,26 @@ impl Config {
             smtp_port: 0,
             email_from: String::new(),
             email_to: String::new(),
+            ignore_peers: Vec::new(),
+            ignore_channels: Vec::new(),
+            maintenance_windows: Vec::new(),
+            quiet_hours: None,
+            timezone: Tz::UTC,
+            report_daily: None,
+            report_weekly: None,
+            outbox_fallback_after: 3,
+            mail_rate_limits: vec![RateLimit::new(10, 60), RateLimit::new(60, 3_600)],
+            telegram_rate_limits: vec![RateLimit::new(20, 60)],
+            templates_dir: "vitality-templates".to_string(),
+            routes: Vec::new(),
+            peer_groups: HashMap::new(),
+            escalate_after: 0,
+            escalate_to: Vec::new(),
+            telegram_ack: false,
+            label: String::new(),
+            node_alias: String::new(),
+            node_id: String::new(),
+            network: String::new(),
             send_mail: false,
             send_telegram: false,
             is_at_or_above_24_11: false,
@@ -44,14 +132,576 @@ impl Config {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+impl FromStr for Severity {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "info" => Ok(Severity::Info),
+            "warning" => Ok(Severity::Warning),
+            "critical" => Ok(Severity::Critical),
+            _ => Err(anyhow!(
+                "{} is not a valid severity, use info, warning or critical",
+                s
+            )),
+        }
+    }
+}
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Info => write!(f, "info"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Critical => write!(f, "critical"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FindingCode {
+    NoLockin,
+    NoReestablish,
+    StatusError,
+    UpdateFee,
+    HtlcStatus,
+    LostState,
+    NoReconnect,
+    ExpiringHtlc,
+    OneSidedGossip,
+    InactiveGossip,
+    NonPublicGossip,
+    NoGossip,
+    ReconnectFailed,
+    ChannelClosed,
+    StuckClose,
+    ResolutionOverdue,
+    UnconfirmedFunding,
+    StuckHtlc,
+    DepletedChannel,
+    NodeImbalance,
+    FlappingPeer,
+}
+impl fmt::Display for FindingCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = match self {
+            FindingCode::NoLockin => "no-lockin",
+            FindingCode::NoReestablish => "no-reestablish",
+            FindingCode::StatusError => "status-error",
+            FindingCode::UpdateFee => "update-fee",
+            FindingCode::HtlcStatus => "htlc-status",
+            FindingCode::LostState => "lost-state",
+            FindingCode::NoReconnect => "no-reconnect",
+            FindingCode::ExpiringHtlc => "expiring-htlc",
+            FindingCode::OneSidedGossip => "one-sided-gossip",
+            FindingCode::InactiveGossip => "inactive-gossip",
+            FindingCode::NonPublicGossip => "non-public-gossip",
+            FindingCode::NoGossip => "no-gossip",
+            FindingCode::ReconnectFailed => "reconnect-failed",
+            FindingCode::ChannelClosed => "channel-closed",
+            FindingCode::StuckClose => "stuck-close",
+            FindingCode::ResolutionOverdue => "resolution-overdue",
+            FindingCode::UnconfirmedFunding => "unconfirmed-funding",
+            FindingCode::StuckHtlc => "stuck-htlc",
+            FindingCode::DepletedChannel => "depleted-channel",
+            FindingCode::NodeImbalance => "node-imbalance",
+            FindingCode::FlappingPeer => "flapping-peer",
+        };
+        write!(f, "{}", code)
+    }
+}
+
+impl FromStr for FindingCode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FindingCode::ALL
+            .into_iter()
+            .find(|c| c.to_string() == s.to_lowercase())
+            .ok_or_else(|| anyhow!("{} is not a valid finding code", s))
+    }
+}
+
+impl FindingCode {
+    pub const ALL: [FindingCode; 21] = [
+        FindingCode::NoLockin,
+        FindingCode::NoReestablish,
+        FindingCode::StatusError,
+        FindingCode::UpdateFee,
+        FindingCode::HtlcStatus,
+        FindingCode::LostState,
+        FindingCode::NoReconnect,
+        FindingCode::ExpiringHtlc,
+        FindingCode::OneSidedGossip,
+        FindingCode::InactiveGossip,
+        FindingCode::NonPublicGossip,
+        FindingCode::NoGossip,
+        FindingCode::ReconnectFailed,
+        FindingCode::ChannelClosed,
+        FindingCode::StuckClose,
+        FindingCode::ResolutionOverdue,
+        FindingCode::UnconfirmedFunding,
+        FindingCode::StuckHtlc,
+        FindingCode::DepletedChannel,
+        FindingCode::NodeImbalance,
+        FindingCode::FlappingPeer,
+    ];
+
+    pub fn severity(&self) -> Severity {
+        match self {
+            FindingCode::LostState
+            | FindingCode::ExpiringHtlc
+            | FindingCode::ChannelClosed
+            | FindingCode::ResolutionOverdue => Severity::Critical,
+            c if c.is_gossip() => Severity::Info,
+            _ => Severity::Warning,
+        }
+    }
+
+    pub fn is_gossip(&self) -> bool {
+        matches!(
+            self,
+            FindingCode::OneSidedGossip
+                | FindingCode::InactiveGossip
+                | FindingCode::NonPublicGossip
+                | FindingCode::NoGossip
+        )
+    }
+
+    /// Reconnecting to the peer can't fix liquidity findings and makes a
+    /// flapping connection worse
+    pub fn reconnects(&self) -> bool {
+        !matches!(
+            self,
+            FindingCode::DepletedChannel | FindingCode::NodeImbalance | FindingCode::FlappingPeer
+        )
+    }
+
+    /// Short description to group findings, e.g. "14 channels with inactive gossip"
+    pub fn description(&self) -> &'static str {
+        match self {
+            FindingCode::NoLockin => "a peer that won't lockin",
+            FindingCode::NoReestablish => "a peer that won't reestablish",
+            FindingCode::StatusError => "an error in status",
+            FindingCode::UpdateFee => "no agreement on fees",
+            FindingCode::HtlcStatus => "an htlc problem",
+            FindingCode::LostState => "lost state",
+            FindingCode::NoReconnect => "a peer that won't reconnect",
+            FindingCode::ExpiringHtlc => "htlcs close to expiry",
+            FindingCode::OneSidedGossip => "one-sided gossip",
+            FindingCode::InactiveGossip => "inactive gossip",
+            FindingCode::NonPublicGossip => "non-public gossip",
+            FindingCode::NoGossip => "no gossip",
+            FindingCode::ReconnectFailed => "a failed reconnect",
+            FindingCode::ChannelClosed => "an unexpected closure",
+            FindingCode::StuckClose => "a stuck cooperative close",
+            FindingCode::ResolutionOverdue => "an overdue onchain resolution",
+            FindingCode::UnconfirmedFunding => "an unconfirmed funding transaction",
+            FindingCode::StuckHtlc => "stuck htlcs",
+            FindingCode::DepletedChannel => "a depleted channel",
+            FindingCode::NodeImbalance => "imbalanced liquidity",
+            FindingCode::FlappingPeer => "a flapping connection",
+        }
+    }
+}
+
+pub type FindingKey = (FindingCode, PublicKey, Option<ShortChannelId>);
+
+#[derive(Clone, Debug)]
+pub struct Finding {
+    pub code: FindingCode,
+    pub peer_id: PublicKey,
+    pub scid: Option<ShortChannelId>,
+    pub message: String,
+    /// Additional values for the message templates, e.g. `status`
+    pub vars: Vec<(&'static str, String)>,
+}
+impl Finding {
+    pub fn key(&self) -> FindingKey {
+        (self.code, self.peer_id, self.scid)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FindingRecord {
+    /// Short id to acknowledge the finding with
+    pub id: u64,
+    pub finding: Finding,
+    pub first_seen: i64,
+    pub last_seen: i64,
+    pub acknowledged: bool,
+    pub escalations: u32,
+    pub escalated_at: i64,
+}
+
+/// Notification with optional HTML and Markdown variants from the message templates
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Message {
+    pub subject: String,
+    pub text: String,
+    pub html: Option<String>,
+    pub markdown: Option<String>,
+}
+impl Message {
+    pub fn plain(subject: String, text: String) -> Message {
+        Message {
+            subject,
+            text,
+            html: None,
+            markdown: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QueuedNotification {
+    pub severity: Severity,
+    pub message: Message,
+    pub queued_at: i64,
+    pub targets: Vec<Target>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Mail,
+    Telegram,
+}
+impl Backend {
+    pub const ALL: [Backend; 2] = [Backend::Mail, Backend::Telegram];
+
+    pub fn configured(&self, config: &Config) -> bool {
+        match self {
+            Backend::Mail => config.send_mail,
+            Backend::Telegram => config.send_telegram,
+        }
+    }
+
+    pub fn rate_limits(&self, config: &Config) -> Vec<RateLimit> {
+        match self {
+            Backend::Mail => config.mail_rate_limits.clone(),
+            Backend::Telegram => config.telegram_rate_limits.clone(),
+        }
+    }
+}
+impl FromStr for Backend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mail" | "email" => Ok(Backend::Mail),
+            "telegram" => Ok(Backend::Telegram),
+            _ => Err(anyhow!(
+                "{} is not a valid backend, use mail or telegram",
+                s
+            )),
+        }
+    }
+}
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Mail => write!(f, "mail"),
+            Backend::Telegram => write!(f, "telegram"),
+        }
+    }
+}
+
+/// Backend and recipients to deliver a notification to, without recipients the
+/// default recipients of the backend are used
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Target {
+    pub backend: Backend,
+    pub recipients: Vec<String>,
+}
+impl Target {
+    pub fn new(backend: Backend) -> Target {
+        Target {
+            backend,
+            recipients: Vec::new(),
+        }
+    }
+
+    pub fn recipients(&self, config: &Config) -> Vec<String> {
+        if !self.recipients.is_empty() {
+            return self.recipients.clone();
+        }
+        match self.backend {
+            Backend::Mail => vec![config.email_to.clone()],
+            Backend::Telegram => config.telegram_usernames.clone(),
+        }
+    }
+}
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.recipients.is_empty() {
+            write!(f, "{}", self.backend)
+        } else {
+            write!(f, "{} ({})", self.backend, self.recipients.join(", "))
+        }
+    }
+}
+
+/// Notification that could not be delivered via `backend` yet
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub id: u64,
+    pub backend: Backend,
+    /// Empty for the default recipients of `backend`
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    pub severity: Severity,
+    pub subject: String,
+    pub body: String,
+    #[serde(default)]
+    pub html: Option<String>,
+    #[serde(default)]
+    pub markdown: Option<String>,
+    pub created_at: i64,
+    pub attempts: u32,
+    pub next_attempt: i64,
+    pub last_error: String,
+    /// Set once a copy was queued for another backend, or if this is that copy
+    pub fallback: bool,
+}
+impl OutboxEntry {
+    pub fn target(&self) -> Target {
+        Target {
+            backend: self.backend,
+            recipients: self.recipients.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PeriodStats {
+    pub since: i64,
+    pub findings_raised: u64,
+    pub findings_resolved: u64,
+    pub reconnects_attempted: u64,
+    pub reconnects_failed: u64,
+    pub reconnects_fixed: u64,
+    pub amboss_pings_ok: u64,
+    pub amboss_pings_failed: u64,
+    pub channels_opened: u64,
+    pub channels_closed: u64,
+}
+impl PeriodStats {
+    pub fn new() -> PeriodStats {
+        PeriodStats {
+            since: Utc::now().timestamp(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Stats {
+    pub daily: PeriodStats,
+    pub weekly: PeriodStats,
+}
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            daily: PeriodStats::new(),
+            weekly: PeriodStats::new(),
+        }
+    }
+
+    pub fn record(&mut self, update: impl Fn(&mut PeriodStats)) {
+        update(&mut self.daily);
+        update(&mut self.weekly);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IgnoreTarget {
+    Peer(PublicKey),
+    Channel(ShortChannelId),
+}
+impl FromStr for IgnoreTarget {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(peer) = PublicKey::from_str(s) {
+            Ok(IgnoreTarget::Peer(peer))
+        } else if let Ok(scid) = ShortChannelId::from_str(s) {
+            Ok(IgnoreTarget::Channel(scid))
+        } else {
+            Err(anyhow!("{} is neither a node id nor a short channel id", s))
+        }
+    }
+}
+impl fmt::Display for IgnoreTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IgnoreTarget::Peer(peer) => write!(f, "{}", peer),
+            IgnoreTarget::Channel(scid) => write!(f, "{}", scid),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IgnoreEntry {
+    pub target: IgnoreTarget,
+    pub until: Option<i64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MuteTarget {
+    Peer(PublicKey),
+    Channel(ShortChannelId),
+    Code(FindingCode),
+}
+impl MuteTarget {
+    pub fn matches(&self, finding: &Finding) -> bool {
+        match self {
+            MuteTarget::Peer(p) => *p == finding.peer_id,
+            MuteTarget::Channel(c) => Some(*c) == finding.scid,
+            MuteTarget::Code(c) => *c == finding.code,
+        }
+    }
+}
+impl FromStr for MuteTarget {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(peer) = PublicKey::from_str(s) {
+            Ok(MuteTarget::Peer(peer))
+        } else if let Ok(scid) = ShortChannelId::from_str(s) {
+            Ok(MuteTarget::Channel(scid))
+        } else if let Ok(code) = FindingCode::from_str(s) {
+            Ok(MuteTarget::Code(code))
+        } else {
+            Err(anyhow!(
+                "{} is neither a node id, a short channel id nor a finding code",
+                s
+            ))
+        }
+    }
+}
+impl fmt::Display for MuteTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MuteTarget::Peer(peer) => write!(f, "{}", peer),
+            MuteTarget::Channel(scid) => write!(f, "{}", scid),
+            MuteTarget::Code(code) => write!(f, "{}", code),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MuteEntry {
+    pub target: MuteTarget,
+    pub until: i64,
+}
+
+/// A peer with channels that is disconnected since `since`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OfflinePeer {
+    pub peer_id: PublicKey,
+    pub since: i64,
+}
+
+/// Connection changes of a peer with channels, oldest first
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PeerUptime {
+    pub peer_id: PublicKey,
+    /// Timestamp and whether the peer was connected from then on
+    pub changes: Vec<(i64, bool)>,
+}
+
+/// Channel, whether it is incoming and id of an htlc, the ids of each direction
+/// are counted separately
+pub type HtlcKey = (ShortChannelId, bool, u64);
+
+/// Since when a channel is in its current state
+#[derive(Clone, Debug)]
+pub struct StateSince {
+    pub state: ChannelState,
+    pub since: i64,
+    pub since_block: u32,
+}
+
 #[derive(Clone)]
 pub struct PluginState {
     pub config: Arc<Mutex<Config>>,
+    pub tasks: Arc<Mutex<HashMap<TaskKind, TaskState>>>,
+    pub startup: Instant,
+    pub check_lock: Arc<tokio::sync::Mutex<()>>,
+    pub last_reconnect: Arc<Mutex<HashMap<PublicKey, Instant>>>,
+    pub reported: Arc<Mutex<HashMap<FindingKey, Instant>>>,
+    pub ignores: Arc<Mutex<Vec<IgnoreEntry>>>,
+    pub mutes: Arc<Mutex<Vec<MuteEntry>>>,
+    /// Channel ids of closed channels we already notified about
+    pub closures: Arc<Mutex<Vec<String>>>,
+    /// Since when channels are in their current state by channel id
+    pub state_since: Arc<Mutex<HashMap<String, StateSince>>>,
+    /// Expected block heights of unresolved outputs of channels in `ONCHAIN`
+    pub resolutions: Arc<Mutex<Resolutions>>,
+    /// When we first saw each pending htlc
+    pub htlcs: Arc<Mutex<HashMap<HtlcKey, i64>>>,
+    /// Block height at which we warned about force-closing each channel for
+    /// `vitality-htlc-force-close`
+    pub force_close_warnings: Arc<Mutex<HashMap<ShortChannelId, u32>>>,
+    /// Since when channels are depleted in one direction
+    pub depletions: Arc<Mutex<Depletions>>,
+    /// Peers with channels that are disconnected and since when
+    pub offline: Arc<Mutex<Vec<OfflinePeer>>>,
+    /// Disconnects of each peer within the last hour
+    pub disconnects: Arc<Mutex<HashMap<PublicKey, Vec<i64>>>>,
+    /// Connection history of peers with channels for the last 30 days
+    pub uptime: Arc<Mutex<Vec<PeerUptime>>>,
+    /// Acknowledged findings, kept until they are resolved
+    pub acks: Arc<Mutex<Vec<FindingKey>>>,
+    pub maintenance_until: Arc<Mutex<Option<i64>>>,
+    pub findings: Arc<Mutex<HashMap<FindingKey, FindingRecord>>>,
+    pub next_finding_id: Arc<Mutex<u64>>,
+    pub quiet_queue: Arc<Mutex<Vec<QueuedNotification>>>,
+    pub stats: Arc<Mutex<Stats>>,
+    pub outbox: Arc<Mutex<Vec<OutboxEntry>>>,
+    /// Held while the outbox is processed, so no entry is sent twice
+    pub outbox_lock: Arc<tokio::sync::Mutex<()>>,
+    pub sent: Arc<Mutex<HashMap<Backend, Vec<i64>>>>,
 }
 impl PluginState {
     pub fn new() -> PluginState {
         PluginState {
             config: Arc::new(Mutex::new(Config::new())),
+            tasks: Arc::new(Mutex::new(HashMap::new())),
+            startup: Instant::now(),
+            check_lock: Arc::new(tokio::sync::Mutex::new(())),
+            last_reconnect: Arc::new(Mutex::new(HashMap::new())),
+            reported: Arc::new(Mutex::new(HashMap::new())),
+            ignores: Arc::new(Mutex::new(Vec::new())),
+            mutes: Arc::new(Mutex::new(Vec::new())),
+            closures: Arc::new(Mutex::new(Vec::new())),
+            state_since: Arc::new(Mutex::new(HashMap::new())),
+            resolutions: Arc::new(Mutex::new(HashMap::new())),
+            htlcs: Arc::new(Mutex::new(HashMap::new())),
+            force_close_warnings: Arc::new(Mutex::new(HashMap::new())),
+            depletions: Arc::new(Mutex::new(HashMap::new())),
+            offline: Arc::new(Mutex::new(Vec::new())),
+            disconnects: Arc::new(Mutex::new(HashMap::new())),
+            uptime: Arc::new(Mutex::new(Vec::new())),
+            acks: Arc::new(Mutex::new(Vec::new())),
+            maintenance_until: Arc::new(Mutex::new(None)),
+            findings: Arc::new(Mutex::new(HashMap::new())),
+            next_finding_id: Arc::new(Mutex::new(1)),
+            quiet_queue: Arc::new(Mutex::new(Vec::new())),
+            stats: Arc::new(Mutex::new(Stats::new())),
+            outbox: Arc::new(Mutex::new(Vec::new())),
+            outbox_lock: Arc::new(tokio::sync::Mutex::new(())),
+            sent: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 }
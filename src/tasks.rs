@@ -0,0 +1,273 @@
+use std::{fmt, panic::AssertUnwindSafe, sync::Arc, time::Duration};
+
+use anyhow::Error;
+use chrono::Utc;
+use cln_plugin::Plugin;
+use futures::FutureExt;
+use log::{info, warn};
+use parking_lot::Mutex;
+use serde_json::json;
+use tokio::{
+    task::JoinHandle,
+    time::{self, Instant},
+};
+
+use crate::{
+    amboss,
+    channelwatch,
+    escalation,
+    notify::{notify, quiet_hours_loop},
+    outbox,
+    report,
+    structs::{Config, Message, PluginState, Severity},
+    templates::Templates,
+    util::panic_message,
+    OPT_AMBOSS,
+    OPT_EXPIRING_HTLCS,
+    OPT_QUIET_HOURS,
+    OPT_REPORT_DAILY,
+    OPT_REPORT_WEEKLY,
+    OPT_TIMEZONE,
+    OPT_WATCH_CHANNELS,
+    OPT_WATCH_GOSSIP,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TaskKind {
+    Amboss,
+    ChannelWatch,
+    QuietHours,
+    Report,
+    Outbox,
+    Escalation,
+}
+impl TaskKind {
+    pub const ALL: [TaskKind; 6] = [
+        TaskKind::Amboss,
+        TaskKind::ChannelWatch,
+        TaskKind::QuietHours,
+        TaskKind::Report,
+        TaskKind::Outbox,
+        TaskKind::Escalation,
+    ];
+
+    pub fn wanted(&self, config: &Config) -> bool {
+        match self {
+            TaskKind::Amboss => config.amboss,
+            TaskKind::ChannelWatch => config.expiring_htlcs > 0 || config.watch_channels,
+            TaskKind::QuietHours => config.quiet_hours.is_some(),
+            TaskKind::Report => config.report_daily.is_some() || config.report_weekly.is_some(),
+            TaskKind::Outbox => config.send_mail || config.send_telegram,
+            TaskKind::Escalation => {
+                config.escalate_after > 0 && (config.send_mail || config.send_telegram)
+            }
+        }
+    }
+
+    fn options(&self) -> &'static [&'static str] {
+        match self {
+            TaskKind::Amboss => &[OPT_AMBOSS],
+            TaskKind::ChannelWatch => &[OPT_EXPIRING_HTLCS, OPT_WATCH_CHANNELS, OPT_WATCH_GOSSIP],
+            TaskKind::QuietHours => &[OPT_QUIET_HOURS, OPT_TIMEZONE],
+            TaskKind::Report => &[OPT_REPORT_DAILY, OPT_REPORT_WEEKLY, OPT_TIMEZONE],
+            TaskKind::Outbox => &[],
+            TaskKind::Escalation => &[],
+        }
+    }
+}
+impl fmt::Display for TaskKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TaskKind::Amboss => write!(f, "amboss_ping_loop"),
+            TaskKind::ChannelWatch => write!(f, "check_channels_loop"),
+            TaskKind::QuietHours => write!(f, "quiet_hours_loop"),
+            TaskKind::Report => write!(f, "report_loop"),
+            TaskKind::Outbox => write!(f, "outbox_loop"),
+            TaskKind::Escalation => write!(f, "escalation_loop"),
+        }
+    }
+}
+
+const BACKOFF_START_S: u64 = 10;
+const BACKOFF_MAX_S: u64 = 3_600;
+const HEALTHY_RUNTIME_S: u64 = 3_600;
+const ESCALATE_AFTER_CRASHES: u32 = 3;
+
+pub struct TaskState {
+    handle: JoinHandle<()>,
+    started_at: i64,
+    stats: Arc<Mutex<TaskStats>>,
+}
+
+#[derive(Default)]
+struct TaskStats {
+    restarts: u32,
+    consecutive_crashes: u32,
+    last_error: Option<String>,
+    last_error_at: Option<i64>,
+}
+
+/// Spawn tasks that should be running but are not, cancel tasks that are no longer
+/// wanted and restart tasks whose option `changed` while they were running.
+pub fn sync_tasks(plugin: &Plugin<PluginState>, changed: Option<&str>) {
+    let config = plugin.state().config.lock().clone();
+    let mut tasks = plugin.state().tasks.lock();
+
+    for kind in TaskKind::ALL {
+        let wanted = kind.wanted(&config);
+        let restart = changed.is_some_and(|c| kind.options().contains(&c));
+
+        if let Some(task) = tasks.get(&kind) {
+            if !task.handle.is_finished() {
+                if wanted && !restart {
+                    continue;
+                }
+                if wanted {
+                    info!("Restarting {} task", kind);
+                } else {
+                    info!("Stopping {} task", kind);
+                }
+                task.handle.abort();
+            }
+            tasks.remove(&kind);
+        }
+
+        if wanted {
+            info!("Starting {} task", kind);
+            let stats = Arc::new(Mutex::new(TaskStats::default()));
+            tasks.insert(
+                kind,
+                TaskState {
+                    handle: tokio::spawn(supervise(plugin.clone(), kind, stats.clone())),
+                    started_at: Utc::now().timestamp(),
+                    stats,
+                },
+            );
+        }
+    }
+}
+
+async fn run_task(plugin: Plugin<PluginState>, kind: TaskKind) -> Result<(), Error> {
+    match kind {
+        TaskKind::Amboss => amboss::amboss_ping_loop(plugin).await,
+        TaskKind::ChannelWatch => channelwatch::check_channels_loop(plugin).await,
+        TaskKind::QuietHours => quiet_hours_loop(plugin).await,
+        TaskKind::Report => report::report_loop(plugin).await,
+        TaskKind::Outbox => outbox::outbox_loop(plugin).await,
+        TaskKind::Escalation => escalation::escalation_loop(plugin).await,
+    }
+}
+
+/// Run the loop of `kind` and restart it with exponential backoff whenever it
+/// returns an error or panics. Escalates via notification if it keeps crashing
+/// and repeats that with every restart once the backoff reached its maximum.
+async fn supervise(plugin: Plugin<PluginState>, kind: TaskKind, stats: Arc<Mutex<TaskStats>>) {
+    let mut backoff_s = BACKOFF_START_S;
+    loop {
+        let now = Instant::now();
+        let error = match AssertUnwindSafe(run_task(plugin.clone(), kind))
+            .catch_unwind()
+            .await
+        {
+            Ok(Ok(())) => {
+                info!("{} finished", kind);
+                return;
+            }
+            Ok(Err(e)) => e.to_string(),
+            Err(panic) => format!("panicked: {}", panic_message(&panic)),
+        };
+        warn!("Error in {} thread: {}", kind, error);
+
+        if now.elapsed().as_secs() >= HEALTHY_RUNTIME_S {
+            backoff_s = BACKOFF_START_S;
+            stats.lock().consecutive_crashes = 0;
+        }
+        let consecutive_crashes = {
+            let mut stats = stats.lock();
+            stats.restarts += 1;
+            stats.consecutive_crashes += 1;
+            stats.last_error = Some(error.clone());
+            stats.last_error_at = Some(Utc::now().timestamp());
+            stats.consecutive_crashes
+        };
+
+        let still_crashing =
+            consecutive_crashes > ESCALATE_AFTER_CRASHES && backoff_s == BACKOFF_MAX_S;
+        if consecutive_crashes == 1
+            || consecutive_crashes == ESCALATE_AFTER_CRASHES
+            || still_crashing
+        {
+            let (severity, template, subject, body) = if consecutive_crashes == 1 {
+                (
+                    Severity::Warning,
+                    "task-error",
+                    format!("ALARM: {} Error", kind),
+                    format!("{}\nRestarting in {}s", error, backoff_s),
+                )
+            } else {
+                (
+                    Severity::Critical,
+                    "task-crashing",
+                    format!("ALARM: {} keeps crashing", kind),
+                    format!(
+                        "{} crashed {} times in a row, last error: {}\n\
+                        Restarting with increasing delays up to {}s",
+                        kind, consecutive_crashes, error, BACKOFF_MAX_S
+                    ),
+                )
+            };
+            let message = Templates::load(&plugin).await.render(
+                template,
+                &[
+                    ("task", kind.to_string()),
+                    ("error", error.clone()),
+                    ("crashes", consecutive_crashes.to_string()),
+                    ("restart_in", backoff_s.to_string()),
+                ],
+                None,
+                Message::plain(subject, body),
+            );
+            notify(&plugin, severity, message).await;
+        }
+
+        info!("Restarting {} in {}s", kind, backoff_s);
+        time::sleep(Duration::from_secs(backoff_s)).await;
+        backoff_s = (backoff_s * 2).min(BACKOFF_MAX_S);
+    }
+}
+
+pub async fn list_tasks(
+    plugin: Plugin<PluginState>,
+    _args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let config = plugin.state().config.lock().clone();
+    let tasks = plugin.state().tasks.lock();
+    let mut result = Vec::new();
+    for kind in TaskKind::ALL {
+        let Some(task) = tasks.get(&kind) else {
+            result.push(json!({
+                "name": kind.to_string(),
+                "enabled": kind.wanted(&config),
+                "state": "stopped",
+            }));
+            continue;
+        };
+        let stats = task.stats.lock();
+        let state = if task.handle.is_finished() {
+            "finished"
+        } else {
+            "running"
+        };
+        result.push(json!({
+            "name": kind.to_string(),
+            "enabled": kind.wanted(&config),
+            "state": state,
+            "started_at": task.started_at,
+            "restarts": stats.restarts,
+            "consecutive_crashes": stats.consecutive_crashes,
+            "last_error": stats.last_error,
+            "last_error_at": stats.last_error_at,
+        }));
+    }
+    Ok(json!({"tasks": result}))
+}
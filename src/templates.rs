@@ -0,0 +1,180 @@
+use std::{collections::HashMap, path::Path};
+
+use cln_plugin::Plugin;
+use log::{debug, warn};
+
+use crate::structs::{Message, PluginState};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Text,
+    Html,
+    Markdown,
+}
+impl Format {
+    const ALL: [Format; 3] = [Format::Text, Format::Html, Format::Markdown];
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Text => "txt",
+            Format::Html => "html",
+            Format::Markdown => "md",
+        }
+    }
+
+    fn escape(&self, value: &str) -> String {
+        match self {
+            Format::Text => value.to_string(),
+            Format::Html => value
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;")
+                .replace('\n', "<br>\n"),
+            Format::Markdown => escape_markdown(value),
+        }
+    }
+}
+
+/// Message templates from `vitality-templates`, named `<alert>.txt`, `<alert>.html`
+/// and `<alert>.md`. A template may start with a `Subject: ...` line.
+#[derive(Debug, Default)]
+pub struct Templates {
+    files: HashMap<String, String>,
+}
+impl Templates {
+    pub async fn load(plugin: &Plugin<PluginState>) -> Templates {
+        let dir = plugin.state().config.lock().templates_dir.clone();
+        let dir = Path::new(&plugin.configuration().lightning_dir).join(dir);
+        let mut files = HashMap::new();
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                debug!("No templates loaded from {}: {}", dir.display(), e);
+                return Templates { files };
+            }
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let Some(name) = entry.file_name().to_str().map(|n| n.to_string()) else {
+                continue;
+            };
+            if !Format::ALL
+                .iter()
+                .any(|f| name.ends_with(&format!(".{}", f.extension())))
+            {
+                continue;
+            }
+            match tokio::fs::read_to_string(entry.path()).await {
+                Ok(content) => {
+                    files.insert(name, content);
+                }
+                Err(e) => warn!("Could not read template {}: {}", entry.path().display(), e),
+            }
+        }
+        Templates { files }
+    }
+
+    fn get(&self, name: &str, format: Format) -> Option<&String> {
+        self.files.get(&format!("{}.{}", name, format.extension()))
+    }
+
+    /// Render the alert `name`. `vars` are escaped for each format, the formatted
+    /// variants of `block` are inserted as `{{block_name}}` without escaping. Without
+    /// a plain text template `default` is used, without HTML or Markdown templates
+    /// the variants of `block` are used if it has them.
+    pub fn render(
+        &self,
+        name: &str,
+        vars: &[(&str, String)],
+        block: Option<(&str, &Message)>,
+        default: Message,
+    ) -> Message {
+        let fill = |template: &str, format: Format| {
+            let mut result = template.to_string();
+            for (key, value) in vars {
+                result = result.replace(&format!("{{{{{}}}}}", key), &format.escape(value));
+            }
+            if let Some((key, message)) = block {
+                let content = match format {
+                    Format::Text => message.text.clone(),
+                    Format::Html => message
+                        .html
+                        .clone()
+                        .unwrap_or_else(|| format.escape(&message.text)),
+                    Format::Markdown => message
+                        .markdown
+                        .clone()
+                        .unwrap_or_else(|| format.escape(&message.text)),
+                };
+                result = result.replace(&format!("{{{{{}}}}}", key), &content);
+            }
+            split_subject(&result)
+        };
+
+        let mut message = default;
+        if let Some(template) = self.get(name, Format::Text) {
+            let (subject, text) = fill(template, Format::Text);
+            message.subject = subject.unwrap_or(message.subject);
+            message.text = text;
+        }
+        message.html = match self.get(name, Format::Html) {
+            Some(template) => Some(fill(template, Format::Html).1),
+            None => block.and_then(|(_, b)| b.html.clone()),
+        };
+        message.markdown = match self.get(name, Format::Markdown) {
+            Some(template) => Some(fill(template, Format::Markdown).1),
+            None => block.and_then(|(_, b)| b.markdown.clone()),
+        };
+        message
+    }
+}
+
+/// Join messages rendered from templates, keeping their HTML and Markdown variants
+pub fn join(messages: &[Message], separator: &str) -> Message {
+    let join_format = |format: Format, variant: fn(&Message) -> &Option<String>| {
+        messages.iter().any(|m| variant(m).is_some()).then(|| {
+            messages
+                .iter()
+                .map(|m| variant(m).clone().unwrap_or_else(|| format.escape(&m.text)))
+                .collect::<Vec<_>>()
+                .join(&format.escape(separator))
+        })
+    };
+    Message {
+        subject: String::new(),
+        text: messages
+            .iter()
+            .map(|m| m.text.as_str())
+            .collect::<Vec<_>>()
+            .join(separator),
+        html: join_format(Format::Html, |m| &m.html),
+        markdown: join_format(Format::Markdown, |m| &m.markdown),
+    }
+}
+
+/// Escape text for telegram's MarkdownV2
+pub fn escape_markdown(value: &str) -> String {
+    value.chars().fold(String::new(), |mut escaped, c| {
+        if "_*[]()~`>#+-=|{}.!\\".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+        escaped
+    })
+}
+
+fn split_subject(content: &str) -> (Option<String>, String) {
+    let mut lines = content.splitn(2, '\n');
+    let first = lines.next().unwrap_or_default();
+    match first.strip_prefix("Subject:") {
+        Some(subject) => (
+            Some(subject.trim().to_string()),
+            lines
+                .next()
+                .unwrap_or_default()
+                .trim_start_matches('\n')
+                .to_string(),
+        ),
+        None => (None, content.to_string()),
+    }
+}
@@ -0,0 +1,232 @@
+use anyhow::Error;
+use chrono::Utc;
+use cln_plugin::Plugin;
+use cln_rpc::{
+    model::{requests::ListnodesRequest, responses::ListpeerchannelsChannels},
+    primitives::PublicKey,
+    ClnRpc,
+};
+use log::warn;
+use serde_json::json;
+
+use crate::{
+    peers::needs_peer,
+    structs::{PeerUptime, PluginState},
+    util::{datastore_load, datastore_save, make_rpc_path},
+};
+
+const DATASTORE_KEY: &str = "uptime";
+/// Windows for the uptime percentages
+const WINDOWS: [(&str, i64); 3] = [("24h", 86_400), ("7d", 604_800), ("30d", 2_592_000)];
+/// Connection changes are kept for the longest window
+const HISTORY_S: i64 = WINDOWS[WINDOWS.len() - 1].1;
+/// Peers listed in the reports, worst uptime first
+const REPORT_PEERS: usize = 10;
+
+pub async fn load_uptime(rpc: &mut ClnRpc) -> Result<Vec<PeerUptime>, Error> {
+    match datastore_load(rpc, DATASTORE_KEY).await? {
+        Some(uptime) => Ok(serde_json::from_str(&uptime)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+async fn save_uptime(plugin: &Plugin<PluginState>) {
+    let result = async {
+        let uptime = serde_json::to_string(&*plugin.state().uptime.lock())?;
+        let mut rpc = ClnRpc::new(make_rpc_path(plugin)).await?;
+        datastore_save(&mut rpc, DATASTORE_KEY, uptime).await
+    }
+    .await;
+    if let Err(e) = result {
+        warn!("Error saving peer uptime: {}", e);
+    }
+}
+
+fn record(uptime: &mut PeerUptime, connected: bool, now: i64) {
+    if uptime.changes.last().is_none_or(|(_, c)| *c != connected) {
+        uptime.changes.push((now, connected));
+    }
+}
+
+/// Remember a connect or disconnect of a tracked peer from the notifications,
+/// these are more precise than the checks
+pub fn record_connection(plugin: &Plugin<PluginState>, peer: PublicKey, connected: bool) {
+    let now = Utc::now().timestamp();
+    let mut uptime = plugin.state().uptime.lock();
+    if let Some(peer_uptime) = uptime.iter_mut().find(|u| u.peer_id == peer) {
+        record(peer_uptime, connected, now);
+    }
+}
+
+/// Start tracking peers with channels and catch connection changes we didn't get
+/// a notification for. `channels` are all our channels, or only those of `peer`
+/// for checks of a single peer. Checks of all channels also drop peers without
+/// channels and connection changes older than 30 days and save the history.
+pub async fn update_uptime(
+    plugin: &Plugin<PluginState>,
+    channels: &[ListpeerchannelsChannels],
+    peer: Option<PublicKey>,
+) {
+    let now = Utc::now().timestamp();
+    {
+        let mut uptime = plugin.state().uptime.lock();
+        let live = channels
+            .iter()
+            .filter(|c| needs_peer(c))
+            .collect::<Vec<_>>();
+        for chan in &live {
+            match uptime.iter_mut().find(|u| u.peer_id == chan.peer_id) {
+                Some(peer_uptime) => record(peer_uptime, chan.peer_connected, now),
+                None => uptime.push(PeerUptime {
+                    peer_id: chan.peer_id,
+                    changes: vec![(now, chan.peer_connected)],
+                }),
+            }
+        }
+        if peer.is_some() {
+            return;
+        }
+        uptime.retain(|u| live.iter().any(|c| c.peer_id == u.peer_id));
+        let cutoff = now - HISTORY_S;
+        for peer_uptime in uptime.iter_mut() {
+            // Keep the state at the cutoff, it lasts into the window
+            let old = peer_uptime
+                .changes
+                .iter()
+                .take_while(|(at, _)| *at <= cutoff)
+                .count();
+            if old > 0 {
+                peer_uptime.changes.drain(..old - 1);
+                peer_uptime.changes[0].0 = cutoff;
+            }
+        }
+    }
+    save_uptime(plugin).await;
+}
+
+/// Percentage of the last `window` seconds `uptime` was connected, `None` if we
+/// don't know anything about that time. Only the tracked part of the window counts.
+fn uptime_percent(uptime: &PeerUptime, window: i64, now: i64) -> Option<f64> {
+    let first = uptime.changes.first()?.0;
+    let start = (now - window).max(first);
+    if start >= now {
+        return None;
+    }
+    let mut connected = 0;
+    for (i, (at, is_connected)) in uptime.changes.iter().enumerate() {
+        if !is_connected {
+            continue;
+        }
+        let until = uptime.changes.get(i + 1).map(|(a, _)| *a).unwrap_or(now);
+        connected += (until.min(now) - (*at).max(start)).max(0);
+    }
+    Some(connected as f64 * 100.0 / (now - start) as f64)
+}
+
+fn format_percent(percent: Option<f64>) -> String {
+    percent
+        .map(|p| format!("{:.1}%", p))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// Uptime of a peer in each of `WINDOWS`
+pub struct PeerStats {
+    pub peer_id: PublicKey,
+    pub alias: Option<String>,
+    pub connected: bool,
+    pub tracked_since: i64,
+    pub percents: Vec<Option<f64>>,
+}
+impl PeerStats {
+    /// One line for the reports, e.g. `02ab.. (alias): 95.2% / 98.1% / 99.0%`
+    pub fn summary(&self) -> String {
+        let name = match &self.alias {
+            Some(alias) => format!("{} ({})", self.peer_id, alias),
+            None => self.peer_id.to_string(),
+        };
+        format!(
+            "{}: {}",
+            name,
+            self.percents
+                .iter()
+                .map(|p| format_percent(*p))
+                .collect::<Vec<_>>()
+                .join(" / ")
+        )
+    }
+}
+
+/// Uptime of all tracked peers, lowest 30 day uptime first
+fn peer_stats(plugin: &Plugin<PluginState>) -> Vec<PeerStats> {
+    let now = Utc::now().timestamp();
+    let uptime = plugin.state().uptime.lock().clone();
+    let mut stats = uptime
+        .iter()
+        .filter_map(|u| {
+            Some(PeerStats {
+                peer_id: u.peer_id,
+                alias: None,
+                connected: u.changes.last()?.1,
+                tracked_since: u.changes.first()?.0,
+                percents: WINDOWS
+                    .iter()
+                    .map(|(_, window)| uptime_percent(u, *window, now))
+                    .collect(),
+            })
+        })
+        .collect::<Vec<_>>();
+    stats.sort_by(|a, b| {
+        let percent = |s: &PeerStats| s.percents.last().copied().flatten().unwrap_or(100.0);
+        percent(a).total_cmp(&percent(b))
+    });
+    stats
+}
+
+async fn add_aliases(rpc: &mut ClnRpc, stats: &mut [PeerStats]) {
+    for peer in stats.iter_mut() {
+        peer.alias = rpc
+            .call_typed(&ListnodesRequest {
+                id: Some(peer.peer_id),
+            })
+            .await
+            .ok()
+            .and_then(|n| n.nodes.into_iter().next())
+            .and_then(|n| n.alias);
+    }
+}
+
+/// Lines for the reports, the peers with the lowest uptime that were not
+/// connected all the time
+pub async fn report_lines(plugin: &Plugin<PluginState>, rpc: &mut ClnRpc) -> Vec<String> {
+    let mut stats = peer_stats(plugin);
+    stats.retain(|s| s.percents.iter().flatten().any(|p| *p < 100.0));
+    stats.truncate(REPORT_PEERS);
+    add_aliases(rpc, &mut stats).await;
+    stats.iter().map(|s| s.summary()).collect()
+}
+
+pub async fn uptime(
+    plugin: Plugin<PluginState>,
+    _args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let mut rpc = ClnRpc::new(make_rpc_path(&plugin)).await?;
+    let mut stats = peer_stats(&plugin);
+    add_aliases(&mut rpc, &mut stats).await;
+    let peers = stats
+        .iter()
+        .map(|s| {
+            let mut peer = json!({
+                "peer_id": s.peer_id.to_string(),
+                "alias": s.alias,
+                "connected": s.connected,
+                "tracked_since": s.tracked_since,
+            });
+            for ((name, _), percent) in WINDOWS.iter().zip(&s.percents) {
+                peer[format!("uptime_{}", name)] =
+                    json!(percent.map(|p| (p * 10.0).round() / 10.0));
+            }
+            peer
+        })
+        .collect::<Vec<_>>();
+    Ok(json!({ "peers": peers }))
+}
@@ -1,10 +1,21 @@
 use std::{
+    any::Any,
+    collections::HashMap,
+    env,
     path::{Path, PathBuf},
     time::Duration,
 };
 
 use anyhow::{anyhow, Error};
+use chrono::{DateTime, Utc};
 use cln_plugin::Plugin;
+use cln_rpc::{
+    model::{
+        requests::{DatastoreMode, DatastoreRequest, ListdatastoreRequest},
+        responses::ListpeerchannelsChannels,
+    },
+    ClnRpc,
+};
 use lettre::{
     message::header::ContentType,
     transport::smtp::{
@@ -17,9 +28,19 @@ use lettre::{
     Tokio1Executor,
 };
 use log::{info, warn};
-use teloxide::{requests::Requester, Bot};
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use teloxide::{payloads::SendMessageSetters, requests::Requester, types::ParseMode, Bot};
+
+use crate::{
+    structs::{Config, PluginState, StateSince, PLUGIN_NAME},
+    templates::escape_markdown,
+};
 
-use crate::structs::{Config, PluginState};
+/// Give lightningd time to reconnect to all peers before we judge them
+pub const STARTUP_GRACE: Duration = Duration::from_secs(600);
+/// Average seconds per block, to estimate block heights from timestamps
+pub const BLOCK_INTERVAL_S: i64 = 600;
 
 // pub async fn get_alias_map(
 //     plugin: Plugin<PluginState>,
@@ -44,6 +65,7 @@ use crate::structs::{Config, PluginState};
 
 pub async fn send_mail(
     config: &Config,
+    recipients: &[String],
     subject: &String,
     body: &String,
     html: bool,
@@ -54,13 +76,14 @@ pub async fn send_mail(
         ContentType::TEXT_PLAIN
     };
 
-    let email = Message::builder()
-        .from(config.email_from.parse().unwrap())
-        .to(config.email_to.parse().unwrap())
+    let mut email = Message::builder().from(config.email_from.parse()?);
+    for recipient in recipients {
+        email = email.to(recipient.parse()?);
+    }
+    let email = email
         .subject(subject.clone())
         .header(header)
-        .body(body.to_string())
-        .unwrap();
+        .body(body.to_string())?;
 
     let creds = Credentials::new(config.smtp_username.clone(), config.smtp_password.clone());
 
@@ -80,7 +103,8 @@ pub async fn send_mail(
     if result.is_ok() {
         info!(
             "Sent email with subject: `{}` to: `{}`",
-            subject, config.email_to
+            subject,
+            recipients.join(", ")
         );
         Ok(())
     } else {
@@ -88,25 +112,272 @@ pub async fn send_mail(
     }
 }
 
-pub async fn send_telegram(config: &Config, subject: &String, body: &String) -> Result<(), Error> {
-    let bot = Bot::new(config.telegram_token.clone());
+/// Telegram rejects messages that are longer than 4096 characters
+const TELEGRAM_MAX_LEN: usize = 4_000;
 
-    for username in &config.telegram_usernames {
-        let mut message = format!("{}\n{}", subject, body);
-        if message.len() > 4000 {
-            message = message[..4000].to_string()
+fn telegram_message(subject: &str, body: &str, markdown: bool) -> String {
+    if markdown {
+        format!("{}\n{}", escape_markdown(subject), body)
+    } else {
+        format!("{}\n{}", subject, body)
+    }
+}
+
+/// Whether the message fits into one telegram message without cutting it, cutting
+/// MarkdownV2 can break escapes and entities so that telegram rejects it
+pub fn fits_telegram(subject: &str, body: &str, markdown: bool) -> bool {
+    telegram_message(subject, body, markdown).len() <= TELEGRAM_MAX_LEN
+}
+
+/// Cut `message` to the telegram limit and mark the cut with an ellipsis. A cut
+/// right after the backslash of an escape in MarkdownV2 drops the backslash too.
+fn truncate_telegram(message: &mut String, markdown: bool) {
+    if message.len() <= TELEGRAM_MAX_LEN {
+        return;
+    }
+    let mut end = (0..=TELEGRAM_MAX_LEN - '…'.len_utf8())
+        .rev()
+        .find(|i| message.is_char_boundary(*i))
+        .unwrap_or_default();
+    if markdown {
+        let backslashes = message[..end]
+            .chars()
+            .rev()
+            .take_while(|c| *c == '\\')
+            .count();
+        if backslashes % 2 == 1 {
+            end -= 1;
         }
-        if let Err(e) = bot.send_message(username.clone(), message).await {
-            warn!("Error sending telegram to {}: {}", username, e);
+    }
+    message.truncate(end);
+    message.push('…');
+}
+
+/// Send to every telegram chat in `chats` and return the result for each chat.
+/// With `markdown` the body is sent as MarkdownV2. Messages over the telegram
+/// limit are cut, check [`fits_telegram`] before sending long MarkdownV2.
+pub async fn send_telegram_chats(
+    config: &Config,
+    chats: &[String],
+    subject: &str,
+    body: &str,
+    markdown: bool,
+) -> Vec<(String, Result<(), Error>)> {
+    let bot = Bot::new(config.telegram_token.clone());
+
+    let mut results = Vec::new();
+    for username in chats {
+        let mut message = telegram_message(subject, body, markdown);
+        truncate_telegram(&mut message, markdown);
+        let request = bot.send_message(username.clone(), message);
+        let request = if markdown {
+            request.parse_mode(ParseMode::MarkdownV2)
+        } else {
+            request
         };
+        let result = match request.await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                warn!("Error sending telegram to {}: {}", username, e);
+                Err(anyhow!(e))
+            }
+        };
+        results.push((username.clone(), result));
     }
+    results
+}
+
+pub async fn datastore_load(rpc: &mut ClnRpc, key: &str) -> Result<Option<String>, Error> {
+    let datastore = rpc
+        .call_typed(&ListdatastoreRequest {
+            key: Some(vec![PLUGIN_NAME.to_string(), key.to_string()]),
+        })
+        .await?
+        .datastore;
+    Ok(datastore.into_iter().next().and_then(|d| d.string))
+}
+
+pub async fn datastore_save(rpc: &mut ClnRpc, key: &str, value: String) -> Result<(), Error> {
+    rpc.call_typed(&DatastoreRequest {
+        generation: None,
+        hex: None,
+        mode: Some(DatastoreMode::CREATE_OR_REPLACE),
+        string: Some(value),
+        key: vec![PLUGIN_NAME.to_string(), key.to_string()],
+    })
+    .await?;
     Ok(())
 }
 
+/// Get an rpc parameter by position or by name
+pub fn get_param<'a>(args: &'a Value, index: usize, name: &str) -> Option<&'a Value> {
+    match args {
+        Value::Array(a) => a.get(index),
+        Value::Object(o) => o.get(name),
+        _ => None,
+    }
+    .filter(|v| !v.is_null())
+}
+
+/// Longest duration we accept, callers add durations to timestamps
+const MAX_DURATION_S: u64 = 100 * 365 * 86_400;
+
+/// Parse durations like `90`, `30s`, `15m`, `2h`, `7d` or `1w` into seconds
+pub fn parse_duration(s: &str) -> Result<u64, Error> {
+    let s = s.trim();
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| anyhow!("{} is not a valid duration", s))?;
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3_600,
+        "d" => 86_400,
+        "w" => 604_800,
+        _ => {
+            return Err(anyhow!(
+                "{} is not a valid duration, unknown unit {}",
+                s,
+                unit
+            ))
+        }
+    };
+    number
+        .checked_mul(multiplier)
+        .filter(|d| *d <= MAX_DURATION_S)
+        .ok_or_else(|| anyhow!("{} is not a valid duration, it is longer than 100 years", s))
+}
+
+/// Duration of an rpc parameter, given in seconds or as a string for [`parse_duration`]
+pub fn duration_param(value: &serde_json::Value) -> Result<u64, Error> {
+    match value.as_u64() {
+        Some(secs) if secs > MAX_DURATION_S => Err(anyhow!(
+            "{} is not a valid duration, it is longer than 100 years",
+            secs
+        )),
+        Some(secs) => Ok(secs),
+        None => parse_duration(value.as_str().unwrap_or_default()),
+    }
+}
+
+/// Timestamp `duration` seconds after `now`
+pub fn timestamp_after(now: i64, duration: u64) -> Result<i64, Error> {
+    i64::try_from(duration)
+        .ok()
+        .and_then(|d| now.checked_add(d))
+        .ok_or_else(|| anyhow!("{}s from now is too far in the future", duration))
+}
+
+/// Format seconds like `8d 2h`, `5h 12m`, `12m` or `30s`
+pub fn format_duration(secs: u64) -> String {
+    let (days, hours, minutes) = (secs / 86_400, secs % 86_400 / 3_600, secs % 3_600 / 60);
+    if days > 0 {
+        if hours > 0 {
+            format!("{}d {}h", days, hours)
+        } else {
+            format!("{}d", days)
+        }
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m", minutes)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// Format a feerate in perkw as sat/vB
+pub fn sat_per_vbyte(perkw: u64) -> String {
+    format!("{:.1}", perkw as f64 * 4.0 / 1_000.0)
+}
+
+/// State changes of `chan`, they are missing in the typed `listpeerchannels`
+pub async fn state_changes(
+    rpc: &mut ClnRpc,
+    chan: &ListpeerchannelsChannels,
+) -> Result<Vec<serde_json::Value>, Error> {
+    let channels: serde_json::Value = rpc
+        .call_raw("listpeerchannels", &json!({"id": chan.peer_id}))
+        .await?;
+    let channel_id = chan.channel_id.map(|c| c.to_string()).unwrap_or_default();
+    let state_changes = channels
+        .get("channels")
+        .and_then(|c| c.as_array())
+        .and_then(|c| {
+            c.iter()
+                .find(|c| c.get("channel_id").and_then(|i| i.as_str()) == Some(channel_id.as_str()))
+        })
+        .and_then(|c| c.get("state_changes"))
+        .and_then(|s| s.as_array())
+        .cloned()
+        .unwrap_or_default();
+    Ok(state_changes)
+}
+
+/// Since when `chan` is in its current state. Taken from its state changes the
+/// first time we see it in that state, the block height is estimated from that
+pub async fn state_since(
+    rpc: &mut ClnRpc,
+    chan: &ListpeerchannelsChannels,
+    since: &Mutex<HashMap<String, StateSince>>,
+    current_blockheight: u32,
+) -> Result<Option<StateSince>, Error> {
+    let Some(channel_id) = chan.channel_id.map(|c| c.to_string()) else {
+        return Ok(None);
+    };
+    let known = since
+        .lock()
+        .get(&channel_id)
+        .filter(|s| s.state == chan.state)
+        .cloned();
+    if known.is_some() {
+        return Ok(known);
+    }
+    let now = Utc::now().timestamp();
+    let timestamp = state_changes(rpc, chan)
+        .await?
+        .last()
+        .and_then(|s| s.get("timestamp"))
+        .and_then(|t| t.as_str())
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+        .map(|t| t.timestamp())
+        .unwrap_or(now);
+    let state_since = StateSince {
+        state: chan.state,
+        since: timestamp,
+        since_block: current_blockheight
+            .saturating_sub(((now - timestamp).max(0) / BLOCK_INTERVAL_S) as u32),
+    };
+    since.lock().insert(channel_id, state_since.clone());
+    Ok(Some(state_since))
+}
+
 pub fn make_rpc_path(plugin: &Plugin<PluginState>) -> PathBuf {
     Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file)
 }
 
+pub fn panic_message(panic: &Box<dyn Any + Send>) -> String {
+    if let Some(msg) = panic.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = panic.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Skip startup delays in the integration tests
+pub fn is_test_debug() -> bool {
+    env::var("TEST_DEBUG")
+        .ok()
+        .and_then(|dbg| parse_boolean(&dbg))
+        .unwrap_or(false)
+}
+
 pub fn parse_boolean(s: &str) -> Option<bool> {
     match s.to_lowercase().as_str() {
         "true" | "1" => Some(true),
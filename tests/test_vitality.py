@@ -1,5 +1,6 @@
 #!/usr/bin/python
 import os
+import time
 
 import pytest
 from pyln.client import RpcError
@@ -114,6 +115,30 @@ def test_options(node_factory, get_plugin):  # noqa: F811
         node.rpc.setconfig("vitality-smtp-port", 99999)
     node.rpc.setconfig("vitality-smtp-port", 9999)
 
+    node.rpc.setconfig("vitality-stuck-close-blocks", 72)
+    node.rpc.setconfig("vitality-stuck-close-after", "6h")
+    with pytest.raises(RpcError, match="is not a valid integer"):
+        node.rpc.setconfig("vitality-stuck-close-blocks", "soon")
+    with pytest.raises(RpcError, match="is not a valid duration"):
+        node.rpc.setconfig("vitality-stuck-close-after", "a while")
+    node.rpc.setconfig("vitality-funding-unconfirmed-blocks", 6)
+    node.rpc.setconfig("vitality-stuck-htlc-after", "30m")
+    with pytest.raises(RpcError, match="is not a valid duration"):
+        node.rpc.setconfig("vitality-stuck-htlc-after", "forever")
+    node.rpc.setconfig("vitality-expiring-htlcs-out", 60)
+    node.rpc.setconfig("vitality-htlc-force-close", 10)
+    with pytest.raises(RpcError, match="is not a valid integer"):
+        node.rpc.setconfig("vitality-htlc-force-close", "late")
+    node.rpc.setconfig("vitality-depleted-after", "12h")
+    node.rpc.setconfig("vitality-channel-min-ratio", 5)
+    node.rpc.setconfig("vitality-node-min-ratio", 10)
+    with pytest.raises(RpcError, match="must be a percentage"):
+        node.rpc.setconfig("vitality-node-min-ratio", 150)
+    node.rpc.setconfig("vitality-offline-thresholds", "1h,1d")
+    with pytest.raises(RpcError, match="is not a valid duration"):
+        node.rpc.setconfig("vitality-offline-thresholds", "1d,a week")
+    node.rpc.setconfig("vitality-flap-limit", 5)
+
     node.rpc.setconfig("vitality-amboss", False)
     with pytest.raises(RpcError) as err:
         node.rpc.setconfig("vitality-amboss", "test")
@@ -146,3 +171,737 @@ def test_email_activation(node_factory, get_plugin):  # noqa: F811
             "plugin-vitality: Will try to send notifications via email"
         )
     )
+
+
+def test_tasks(node_factory, get_plugin):  # noqa: F811
+    os.environ["TEST_DEBUG"] = "true"
+    node = node_factory.get_node(
+        options={
+            "plugin": get_plugin,
+            "vitality-watch-channels": "false",
+        }
+    )
+
+    tasks = {t["name"]: t for t in node.rpc.call("vitality-tasks")["tasks"]}
+    assert tasks["amboss_ping_loop"]["state"] == "stopped"
+    assert tasks["check_channels_loop"]["state"] == "stopped"
+
+    node.rpc.setconfig("vitality-amboss", True)
+    wait_for(lambda: node.daemon.is_in_log(r"Starting amboss_ping_loop task"))
+    tasks = {t["name"]: t for t in node.rpc.call("vitality-tasks")["tasks"]}
+    assert tasks["amboss_ping_loop"]["state"] == "running"
+    assert tasks["amboss_ping_loop"]["enabled"]
+
+    node.rpc.setconfig("vitality-amboss", False)
+    wait_for(lambda: node.daemon.is_in_log(r"Stopping amboss_ping_loop task"))
+    tasks = {t["name"]: t for t in node.rpc.call("vitality-tasks")["tasks"]}
+    assert tasks["amboss_ping_loop"]["state"] == "stopped"
+
+    node.rpc.setconfig("vitality-watch-channels", True)
+    wait_for(
+        lambda: node.daemon.is_in_log(r"Starting check_channels_loop task")
+    )
+    node.rpc.setconfig("vitality-watch-gossip", True)
+    wait_for(
+        lambda: node.daemon.is_in_log(r"Restarting check_channels_loop task")
+    )
+
+
+def test_ignore(node_factory, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts=[
+            {
+                "plugin": get_plugin,
+                "vitality-ignore-channels": "103x1x0, 104x2x1",
+            },
+            {},
+        ],
+    )
+    ignores = l1.rpc.call("vitality-ignore")["ignores"]
+    assert len(ignores) == 2
+    assert all(i["source"] == "config" for i in ignores)
+
+    l1.rpc.call("vitality-ignore", ["add", l2.info["id"], "1h"])
+    ignores = l1.rpc.call("vitality-ignore", {"action": "list"})["ignores"]
+    peer = [i for i in ignores if i["type"] == "peer"]
+    assert len(peer) == 1
+    assert peer[0]["target"] == l2.info["id"]
+    assert peer[0]["until"] is not None
+
+    l1.rpc.call("vitality-ignore", ["add", "105x1x0"])
+    l1.restart()
+    ignores = l1.rpc.call("vitality-ignore")["ignores"]
+    assert len([i for i in ignores if i["source"] == "rpc"]) == 2
+
+    l1.rpc.call("vitality-ignore", ["remove", l2.info["id"]])
+    with pytest.raises(RpcError, match="is not ignored"):
+        l1.rpc.call("vitality-ignore", ["remove", l2.info["id"]])
+    with pytest.raises(RpcError, match="neither a node id nor a short"):
+        l1.rpc.call("vitality-ignore", ["add", "test"])
+    with pytest.raises(RpcError, match="longer than 100 years"):
+        l1.rpc.call("vitality-ignore", ["add", "105x1x0", "99999999999999999w"])
+    with pytest.raises(RpcError, match="longer than 100 years"):
+        l1.rpc.call("vitality-ignore", ["add", "105x1x0", 18446744073709551615])
+
+    with pytest.raises(RpcError, match="is not a valid node id"):
+        l1.rpc.setconfig("vitality-ignore-peers", "test")
+
+
+def test_maintenance(node_factory, get_plugin):  # noqa: F811
+    node = node_factory.get_node(
+        options={
+            "plugin": get_plugin,
+            "vitality-maintenance-windows": "0 4 * * 0 30m; */15 * 1 * * 5m",
+        }
+    )
+    status = node.rpc.call("vitality-maintenance")
+    assert len(status["windows"]) == 2
+    assert all(w["next_start"] is not None for w in status["windows"])
+
+    status = node.rpc.call("vitality-maintenance", ["start", "2h"])
+    assert status["active"]
+    assert status["manual_until"] is not None
+
+    node.restart()
+    assert node.rpc.call("vitality-maintenance")["manual_until"] is not None
+
+    node.rpc.setconfig("vitality-maintenance-windows", "")
+    status = node.rpc.call("vitality-maintenance", {"action": "stop"})
+    assert not status["active"]
+    assert status["manual_until"] is None
+    assert status["windows"] == []
+
+    with pytest.raises(RpcError, match="is not a valid cron value"):
+        node.rpc.setconfig("vitality-maintenance-windows", "0 25 * * * 1h")
+    with pytest.raises(RpcError, match="Unknown action"):
+        node.rpc.call("vitality-maintenance", ["begin"])
+    with pytest.raises(RpcError, match="longer than 100 years"):
+        node.rpc.call("vitality-maintenance", ["start", 18446744073709551615])
+
+
+def test_quiet_hours(node_factory, get_plugin):  # noqa: F811
+    node = node_factory.get_node(
+        options={
+            "plugin": get_plugin,
+            "vitality-quiet-hours": "22:00-07:00",
+            "vitality-timezone": "Europe/Berlin",
+        }
+    )
+    tasks = {t["name"]: t for t in node.rpc.call("vitality-tasks")["tasks"]}
+    assert tasks["quiet_hours_loop"]["state"] == "running"
+
+    with pytest.raises(RpcError, match="is not a valid IANA timezone"):
+        node.rpc.setconfig("vitality-timezone", "Mars/Olympus")
+    with pytest.raises(RpcError, match="same start and end time"):
+        node.rpc.setconfig("vitality-quiet-hours", "07:00-07:00")
+    with pytest.raises(RpcError, match="is not a valid time"):
+        node.rpc.setconfig("vitality-quiet-hours", "7pm-7am")
+
+    node.rpc.setconfig("vitality-quiet-hours", "")
+    wait_for(lambda: node.daemon.is_in_log(r"Stopping quiet_hours_loop task"))
+
+
+def test_report(node_factory, get_plugin):  # noqa: F811
+    node = node_factory.get_node(
+        options={
+            "plugin": get_plugin,
+            "vitality-report-daily": "08:00",
+            "vitality-report-weekly": "mon 08:00",
+        }
+    )
+    tasks = {t["name"]: t for t in node.rpc.call("vitality-tasks")["tasks"]}
+    assert tasks["report_loop"]["state"] == "running"
+
+    report = node.rpc.call("vitality-report")
+    assert report["period"] == "daily"
+    assert report["stats"]["findings_raised"] == 0
+    assert report["health"]["channels_normal"] == 0
+    assert "Summary since" in report["report"]
+    assert node.rpc.call("vitality-report", ["weekly"])["period"] == "weekly"
+
+    with pytest.raises(RpcError, match="Unknown period"):
+        node.rpc.call("vitality-report", ["monthly"])
+    with pytest.raises(RpcError, match="is not a valid weekday"):
+        node.rpc.setconfig("vitality-report-weekly", "someday 08:00")
+    with pytest.raises(RpcError, match="is not a valid time"):
+        node.rpc.setconfig("vitality-report-daily", "8am")
+
+    node.rpc.setconfig("vitality-report-daily", "")
+    node.rpc.setconfig("vitality-report-weekly", "")
+    wait_for(lambda: node.daemon.is_in_log(r"Stopping report_loop task"))
+
+
+def test_outbox(node_factory, get_plugin):  # noqa: F811
+    node = node_factory.get_node(
+        options={
+            "plugin": get_plugin,
+            "vitality-outbox-fallback-after": 2,
+        }
+    )
+    assert node.rpc.call("vitality-outbox")["outbox"] == []
+    assert node.rpc.call("vitality-outbox", ["retry"])["outbox"] == []
+    assert node.rpc.call("vitality-outbox", {"action": "drop"})["outbox"] == []
+
+    with pytest.raises(RpcError, match="No notification with id 1 in outbox"):
+        node.rpc.call("vitality-outbox", ["drop", 1])
+    with pytest.raises(RpcError, match="Unknown action"):
+        node.rpc.call("vitality-outbox", ["send"])
+    with pytest.raises(RpcError, match="is not a valid integer"):
+        node.rpc.setconfig("vitality-outbox-fallback-after", "often")
+    node.rpc.setconfig("vitality-outbox-fallback-after", 0)
+
+
+def test_testnotifications(node_factory, get_plugin):  # noqa: F811
+    node = node_factory.get_node(options={"plugin": get_plugin})
+    result = node.rpc.call("vitality-testnotifications")
+    assert result["result"] == "nothing sent"
+    assert result["severity"] == "warning"
+    assert result["held"] is None
+    backends = {b["backend"]: b for b in result["backends"]}
+    assert not backends["mail"]["configured"]
+    assert backends["telegram"]["recipients"] == []
+
+    node.rpc.call("vitality-maintenance", ["start", "10m"])
+    result = node.rpc.call("vitality-testnotifications", {"severity": "critical"})
+    assert result["held"] == "maintenance"
+
+    with pytest.raises(RpcError, match="telegram is not configured"):
+        node.rpc.call("vitality-testnotifications", ["telegram"])
+    with pytest.raises(RpcError, match="is not a valid backend"):
+        node.rpc.call("vitality-testnotifications", ["pigeon"])
+    with pytest.raises(RpcError, match="is not a valid severity"):
+        node.rpc.call("vitality-testnotifications", ["all", "urgent"])
+
+
+def test_rate_limits(node_factory, get_plugin):  # noqa: F811
+    node = node_factory.get_node(
+        options={
+            "plugin": get_plugin,
+            "vitality-mail-rate-limit": "5/m,30/1h",
+        }
+    )
+    node.rpc.setconfig("vitality-telegram-rate-limit", "10/30s")
+    node.rpc.setconfig("vitality-telegram-rate-limit", "")
+
+    with pytest.raises(RpcError, match="is not a valid rate limit"):
+        node.rpc.setconfig("vitality-mail-rate-limit", "5 per minute")
+    with pytest.raises(RpcError, match="is not a valid message count"):
+        node.rpc.setconfig("vitality-mail-rate-limit", "0/m")
+    with pytest.raises(RpcError, match="is not a valid duration"):
+        node.rpc.setconfig("vitality-telegram-rate-limit", "20/fortnight")
+
+
+def test_templates(node_factory, get_plugin):  # noqa: F811
+    node = node_factory.get_node(options={"plugin": get_plugin})
+    template_dir = os.path.join(node.daemon.lightning_dir, "vitality-templates")
+    os.makedirs(template_dir)
+    with open(os.path.join(template_dir, "test.txt"), "w") as f:
+        f.write("Subject: Probe {{severity}}\nTesting {{severity}}\n")
+
+    result = node.rpc.call("vitality-testnotifications", {"severity": "info"})
+    assert result["result"] == "nothing sent"
+    assert result["subject"].endswith("Probe info")
+
+    node.rpc.setconfig("vitality-templates-dir", "/nonexistent")
+    result = node.rpc.call("vitality-testnotifications")
+    assert result["result"] == "nothing sent"
+    assert result["subject"].endswith("Test Notification")
+
+
+def test_identity(node_factory, get_plugin):  # noqa: F811
+    node = node_factory.get_node(options={"plugin": get_plugin})
+    info = node.rpc.getinfo()
+    result = node.rpc.call("vitality-testnotifications")
+    assert result["subject"] == "[{} {} {}] Test Notification".format(
+        info["alias"], info["id"][:8], info["network"]
+    )
+
+    node.rpc.setconfig("vitality-label", "backup-node")
+    result = node.rpc.call("vitality-testnotifications")
+    assert result["subject"].startswith("[backup-node {} ".format(info["id"][:8]))
+
+
+def test_routing(node_factory, get_plugin):  # noqa: F811
+    node = node_factory.get_node(
+        options={
+            "plugin": get_plugin,
+            "vitality-telegram-token": "4582169472:Og4grGKROE3OR-x-O3kfOsks",
+            "vitality-telegram-usernames": "936723718",
+            "vitality-peer-groups": "alice={}".format("02" + "11" * 32),
+            "vitality-routes": "critical=telegram:111,telegram:222;group:alice=mail",
+        }
+    )
+    result = node.rpc.call("vitality-testnotifications", {"severity": "critical"})
+    backends = {b["backend"]: b for b in result["backends"]}
+    assert backends["telegram"]["routed"]
+    assert [r["recipient"] for r in backends["telegram"]["recipients"]] == [
+        "111",
+        "222",
+    ]
+
+    result = node.rpc.call("vitality-testnotifications", {"severity": "info"})
+    backends = {b["backend"]: b for b in result["backends"]}
+    assert [r["recipient"] for r in backends["telegram"]["recipients"]] == [
+        "936723718"
+    ]
+
+    with pytest.raises(RpcError, match="is not a valid route condition"):
+        node.rpc.setconfig("vitality-routes", "urgent=telegram")
+    with pytest.raises(RpcError, match="is not a valid backend"):
+        node.rpc.setconfig("vitality-routes", "critical=pigeon")
+    with pytest.raises(RpcError, match="is not a valid route"):
+        node.rpc.setconfig("vitality-routes", "critical")
+    with pytest.raises(RpcError, match="is not a valid peer group"):
+        node.rpc.setconfig("vitality-peer-groups", "alice")
+
+
+def test_escalation(node_factory, get_plugin):  # noqa: F811
+    node = node_factory.get_node(
+        options={
+            "plugin": get_plugin,
+            "vitality-telegram-token": "4582169472:Og4grGKROE3OR-x-O3kfOsks",
+            "vitality-telegram-usernames": "936723718",
+        }
+    )
+    tasks = {t["name"]: t for t in node.rpc.call("vitality-tasks")["tasks"]}
+    assert tasks["escalation_loop"]["state"] == "stopped"
+
+    node.rpc.setconfig("vitality-escalate-after", "15m")
+    wait_for(lambda: node.daemon.is_in_log(r"Starting escalation_loop task"))
+    node.rpc.setconfig("vitality-escalate-to", "telegram:111,mail:oncall@example.com")
+
+    assert node.rpc.call("vitality-ack", ["all"])["acknowledged"] == []
+    with pytest.raises(RpcError, match="No finding with id 42"):
+        node.rpc.call("vitality-ack", [42])
+    with pytest.raises(RpcError, match="is not a valid finding id"):
+        node.rpc.call("vitality-ack", ["everything"])
+    with pytest.raises(RpcError, match="is not a valid backend"):
+        node.rpc.setconfig("vitality-escalate-to", "pager")
+    with pytest.raises(RpcError, match="is not a valid duration"):
+        node.rpc.setconfig("vitality-escalate-after", "soon")
+    with pytest.raises(RpcError, match="is not a valid boolean"):
+        node.rpc.setconfig("vitality-telegram-ack", "maybe")
+
+    # the bot is only polled for /ack once it is switched on
+    assert not node.daemon.is_in_log(r"Error getting telegram updates")
+    node.rpc.setconfig("vitality-telegram-ack", True)
+    wait_for(lambda: node.daemon.is_in_log(r"Error getting telegram updates"))
+
+    node.rpc.setconfig("vitality-escalate-after", "")
+    wait_for(lambda: node.daemon.is_in_log(r"Stopping escalation_loop task"))
+
+
+def test_mute(node_factory, get_plugin):  # noqa: F811
+    node = node_factory.get_node(options={"plugin": get_plugin})
+    peer = "02" + "11" * 32
+
+    result = node.rpc.call("vitality-mute", [peer, "2h"])
+    assert result["target"] == peer
+    node.rpc.call("vitality-mute", {"target": "inactive-gossip", "duration": "1d"})
+    node.rpc.call("vitality-mute", ["103x1x0", "30m"])
+    mutes = node.rpc.call("vitality-listmutes")
+    assert {m["type"] for m in mutes["mutes"]} == {"peer", "code", "channel"}
+    assert mutes["acknowledged"] == []
+
+    node.rpc.call("vitality-mute", ["103x1x0", "0"])
+    assert len(node.rpc.call("vitality-listmutes")["mutes"]) == 2
+
+    with pytest.raises(RpcError, match="nor a finding code"):
+        node.rpc.call("vitality-mute", ["nobody", "1h"])
+    with pytest.raises(RpcError, match="Missing duration"):
+        node.rpc.call("vitality-mute", [peer])
+    with pytest.raises(RpcError, match="longer than 100 years"):
+        node.rpc.call("vitality-mute", [peer, 18446744073709551615])
+
+    node.restart()
+    assert len(node.rpc.call("vitality-listmutes")["mutes"]) == 2
+
+
+def test_channel_closed(node_factory, bitcoind, get_plugin):  # noqa: F811
+    os.environ["TEST_DEBUG"] = "true"
+    opts = {"plugin": get_plugin, "vitality-watch-channels": "true"}
+    l1, l2 = node_factory.get_nodes(2, opts=[opts, opts])
+    l2.fundwallet(10_000_000)
+    l2.rpc.fundchannel(
+        l1.info["id"] + "@localhost:" + str(l1.port),
+        1_000_000,
+        mindepth=1,
+    )
+    bitcoind.generate_block(6)
+    sync_blockheight(bitcoind, [l1, l2])
+    wait_for(
+        lambda: l1.rpc.listpeerchannels(l2.info["id"])["channels"][0]["state"]
+        == "CHANNELD_NORMAL"
+    )
+    assert l2.rpc.call("vitality-onchain")["channels"] == []
+
+    # closures during maintenance are notified once the maintenance is over
+    l2.rpc.call("vitality-maintenance", ["start", "1h"])
+    l2.rpc.dev_fail(l1.info["id"])
+    bitcoind.generate_block(1, wait_for_mempool=1)
+    sync_blockheight(bitcoind, [l1, l2])
+    l1.daemon.wait_for_log(r"check_closures: .*Channel force-closed by the peer")
+    l2.daemon.wait_for_log(r"check_closures: Maintenance until .*, notifying about 1 closures")
+    assert not l2.daemon.is_in_log(r"check_closures: .*Channel force-closed by")
+    l2.rpc.call("vitality-maintenance", ["stop"])
+
+    # our delayed output is expected back after to_self_delay
+    bitcoind.generate_block(1)
+    l2.daemon.wait_for_log(r"check_closures: .*Channel force-closed by")
+    wait_for(
+        lambda: [
+            c["expected_block"]
+            for c in l2.rpc.call("vitality-onchain")["channels"]
+        ]
+        not in ([], [None])
+    )
+    onchain = l2.rpc.call("vitality-onchain")
+    assert onchain["channels"][0]["expected_block"] > onchain["blockheight"]
+    assert "Onchain funds: " in l2.rpc.call("vitality-report")["report"]
+
+    # every closure is only notified once, also after a restart
+    l1.restart()
+    bitcoind.generate_block(1)
+    sync_blockheight(bitcoind, [l1])
+    l1.daemon.wait_for_log(r"check_channel: Starting")
+    assert len([line for line in l1.daemon.logs if "check_closures: " in line]) == 1
+
+
+def test_stuck_close(node_factory, bitcoind, executor, get_plugin):  # noqa: F811
+    os.environ["TEST_DEBUG"] = "true"
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts=[
+            {
+                "plugin": get_plugin,
+                "vitality-watch-channels": "true",
+                "vitality-stuck-close-after": "1s",
+            },
+            {"may_fail": True},
+        ],
+    )
+    l1.fundwallet(10_000_000)
+    l1.rpc.fundchannel(l2.info["id"] + "@localhost:" + str(l2.port), 1_000_000)
+    bitcoind.generate_block(6)
+    sync_blockheight(bitcoind, [l1, l2])
+    wait_for(
+        lambda: l1.rpc.listpeerchannels(l2.info["id"])["channels"][0]["state"]
+        == "CHANNELD_NORMAL"
+    )
+
+    # the close can't progress while the peer is gone
+    l2.stop()
+    executor.submit(l1.rpc.close, l2.info["id"])
+    wait_for(
+        lambda: l1.rpc.listpeerchannels(l2.info["id"])["channels"][0]["state"]
+        == "CHANNELD_SHUTTING_DOWN"
+    )
+
+    def stuck_found():
+        bitcoind.generate_block(1)
+        return l1.daemon.is_in_log(
+            r"Cooperative close stuck in CHANNELD_SHUTTING_DOWN for 0h"
+        )
+
+    wait_for(stuck_found)
+    wait_for(
+        lambda: "stuck-close"
+        in [f["code"] for f in l1.rpc.call("vitality-findings")["findings"]]
+    )
+
+
+def test_unconfirmed_funding(node_factory, bitcoind, get_plugin):  # noqa: F811
+    os.environ["TEST_DEBUG"] = "true"
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts=[
+            {
+                "plugin": get_plugin,
+                "vitality-watch-channels": "true",
+                "vitality-funding-unconfirmed-blocks": 3,
+            },
+            {},
+        ],
+    )
+    l1.fundwallet(10_000_000)
+    l1.rpc.fundchannel(
+        l2.info["id"] + "@localhost:" + str(l2.port),
+        1_000_000,
+        feerate="253perkw",
+    )
+    bitcoind.generate_block(3, needfeerate=100_000)
+    sync_blockheight(bitcoind, [l1])
+    l1.daemon.wait_for_log(
+        r"Funding transaction .* unconfirmed for \d+ blocks.* Bump it with CPFP"
+    )
+    findings = l1.rpc.call("vitality-findings")["findings"]
+    assert [f["code"] for f in findings] == ["unconfirmed-funding"]
+
+
+def test_depleted_channel(node_factory, bitcoind, get_plugin):  # noqa: F811
+    os.environ["TEST_DEBUG"] = "true"
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts=[
+            {
+                "plugin": get_plugin,
+                "vitality-watch-channels": "true",
+                "vitality-depleted-after": "1s",
+                "vitality-node-min-ratio": 10,
+            },
+            {},
+        ],
+    )
+    l1.fundwallet(10_000_000)
+    l1.rpc.fundchannel(l2.info["id"] + "@localhost:" + str(l2.port), 1_000_000)
+    bitcoind.generate_block(6)
+    sync_blockheight(bitcoind, [l1, l2])
+    wait_for(
+        lambda: l1.rpc.listpeerchannels(l2.info["id"])["channels"][0]["state"]
+        == "CHANNELD_NORMAL"
+    )
+    time.sleep(2)
+    bitcoind.generate_block(1)
+    l1.daemon.wait_for_log(r"without inbound liquidity for 0h")
+    l1.daemon.wait_for_log(r"Only 0% of the usable liquidity of the node is inbound")
+    findings = l1.rpc.call("vitality-findings")["findings"]
+    assert sorted(f["code"] for f in findings) == ["depleted-channel", "node-imbalance"]
+    assert not l1.daemon.is_in_log(r"disconnecting from")
+
+
+def test_offline_peers(node_factory, bitcoind, get_plugin):  # noqa: F811
+    os.environ["TEST_DEBUG"] = "true"
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts=[
+            {
+                "plugin": get_plugin,
+                "vitality-watch-channels": "true",
+                "vitality-offline-thresholds": "1s,1d",
+            },
+            {},
+        ],
+    )
+    l1.fundwallet(10_000_000)
+    l1.rpc.fundchannel(l2.info["id"] + "@localhost:" + str(l2.port), 1_000_000)
+    bitcoind.generate_block(6)
+    sync_blockheight(bitcoind, [l1, l2])
+    wait_for(
+        lambda: l1.rpc.listpeerchannels(l2.info["id"])["channels"][0]["state"]
+        == "CHANNELD_NORMAL"
+    )
+    assert l1.rpc.call("vitality-offline")["peers"] == []
+
+    l2.stop()
+    bitcoind.generate_block(1)
+    l1.daemon.wait_for_log(r"went offline")
+    time.sleep(2)
+    peers = l1.rpc.call("vitality-offline")["peers"]
+    assert [p["peer_id"] for p in peers] == [l2.info["id"]]
+    assert peers[0]["threshold"] == "1s"
+    assert peers[0]["channels"] == 1
+    assert peers[0]["locked_msat"] > 0
+    assert "Peers offline for long: 1" in l1.rpc.call("vitality-report")["report"]
+
+    since = peers[0]["since"]
+    l1.restart()
+    assert l1.rpc.call("vitality-offline")["peers"][0]["since"] == since
+
+
+def test_flapping_peer(node_factory, bitcoind, get_plugin):  # noqa: F811
+    os.environ["TEST_DEBUG"] = "true"
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts=[
+            {
+                "plugin": get_plugin,
+                "vitality-watch-channels": "true",
+                "vitality-flap-limit": 2,
+            },
+            {},
+        ],
+    )
+    l1.fundwallet(10_000_000)
+    l1.rpc.fundchannel(l2.info["id"] + "@localhost:" + str(l2.port), 1_000_000)
+    bitcoind.generate_block(6)
+    sync_blockheight(bitcoind, [l1, l2])
+    wait_for(
+        lambda: l1.rpc.listpeerchannels(l2.info["id"])["channels"][0]["state"]
+        == "CHANNELD_NORMAL"
+    )
+    for _ in range(3):
+        l1.rpc.disconnect(l2.info["id"], force=True)
+        l1.rpc.connect(l2.info["id"], "localhost", l2.port)
+    bitcoind.generate_block(1)
+    l1.daemon.wait_for_log(r"disconnected 3 times in the last hour")
+    findings = l1.rpc.call("vitality-findings")["findings"]
+    assert [f["code"] for f in findings] == ["flapping-peer"]
+    assert not l1.daemon.is_in_log(r"disconnecting from")
+
+
+def test_event_checks(node_factory, bitcoind, get_plugin):  # noqa: F811
+    os.environ["TEST_DEBUG"] = "true"
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts=[
+            {
+                "plugin": get_plugin,
+                "vitality-watch-channels": "true",
+            },
+            {},
+        ],
+    )
+    l1.fundwallet(10_000_000)
+    l1.rpc.fundchannel(l2.info["id"] + "@localhost:" + str(l2.port), 1_000_000)
+    bitcoind.generate_block(6)
+    sync_blockheight(bitcoind, [l1, l2])
+    wait_for(
+        lambda: l1.rpc.listpeerchannels(l2.info["id"])["channels"][0]["state"]
+        == "CHANNELD_NORMAL"
+    )
+
+    bitcoind.generate_block(1)
+    sync_blockheight(bitcoind, [l1])
+    l1.daemon.wait_for_logs(
+        [r"check_channel: Starting Block", r"check_channel: Block all good"]
+    )
+
+    # the disconnect and the reconnect both check the peer after they settled
+    l1.rpc.disconnect(l2.info["id"], force=True)
+    l1.daemon.wait_for_logs(
+        [r"check_channel: Starting Peer\(", r"check_channel: Starting Peer\("]
+    )
+    assert l1.rpc.call("vitality-findings")["findings"] == []
+
+
+def test_uptime(node_factory, bitcoind, get_plugin):  # noqa: F811
+    os.environ["TEST_DEBUG"] = "true"
+    l1, l2 = node_factory.get_nodes(
+        2, opts=[{"plugin": get_plugin, "vitality-watch-channels": "true"}, {}]
+    )
+    l1.fundwallet(10_000_000)
+    l1.rpc.fundchannel(l2.info["id"] + "@localhost:" + str(l2.port), 1_000_000)
+    bitcoind.generate_block(6)
+    sync_blockheight(bitcoind, [l1, l2])
+    wait_for(
+        lambda: l1.rpc.listpeerchannels(l2.info["id"])["channels"][0]["state"]
+        == "CHANNELD_NORMAL"
+    )
+    wait_for(lambda: len(l1.rpc.call("vitality-uptime")["peers"]) == 1)
+    time.sleep(2)
+    peer = l1.rpc.call("vitality-uptime")["peers"][0]
+    assert peer["peer_id"] == l2.info["id"]
+    assert peer["connected"]
+    assert peer["uptime_24h"] == 100.0
+
+    l2.stop()
+    wait_for(lambda: not l1.rpc.call("vitality-uptime")["peers"][0]["connected"])
+    time.sleep(2)
+    peer = l1.rpc.call("vitality-uptime")["peers"][0]
+    assert peer["uptime_24h"] < 100.0
+    assert peer["uptime_30d"] == peer["uptime_24h"]
+    assert "Lowest peer uptime" in l1.rpc.call("vitality-report")["report"]
+    assert l2.info["id"] in l1.rpc.call("vitality-report")["report"]
+
+    bitcoind.generate_block(1)
+    l1.daemon.wait_for_log(r"went offline")
+    time.sleep(1)
+    tracked_since = peer["tracked_since"]
+    l1.restart()
+    peer = l1.rpc.call("vitality-uptime")["peers"][0]
+    assert peer["tracked_since"] == tracked_since
+
+
+def stuck_htlc(node_factory, bitcoind, opts):
+    """l1 with a channel to l2 and an outgoing htlc that l2 never resolves"""
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts=[
+            dict(opts, **{"dev-no-reconnect": None}),
+            {"disconnect": ["-WIRE_UPDATE_FULFILL_HTLC"], "may_fail": True},
+        ],
+    )
+    l1.fundwallet(10_000_000)
+    l1.rpc.fundchannel(l2.info["id"] + "@localhost:" + str(l2.port), 1_000_000)
+    bitcoind.generate_block(6)
+    sync_blockheight(bitcoind, [l1, l2])
+    wait_for(
+        lambda: l1.rpc.listpeerchannels(l2.info["id"])["channels"][0]["state"]
+        == "CHANNELD_NORMAL"
+    )
+    inv = l2.rpc.invoice(100_000_000, "stuck", "stuck")
+    route = l1.rpc.getroute(l2.info["id"], 100_000_000, 1, cltv=20)["route"]
+    l1.rpc.sendpay(route, inv["payment_hash"], payment_secret=inv["payment_secret"])
+    l2.daemon.wait_for_log(r"dev_disconnect: -WIRE_UPDATE_FULFILL_HTLC")
+    l2.stop()
+    wait_for(
+        lambda: len(l1.rpc.listpeerchannels(l2.info["id"])["channels"][0]["htlcs"])
+        == 1
+    )
+    return l1, l2
+
+
+def test_stuck_htlc(node_factory, bitcoind, get_plugin):  # noqa: F811
+    os.environ["TEST_DEBUG"] = "true"
+    l1, l2 = stuck_htlc(
+        node_factory,
+        bitcoind,
+        {
+            "plugin": get_plugin,
+            "vitality-watch-channels": "true",
+            "vitality-stuck-htlc-after": "1s",
+        },
+    )
+
+    # every block triggers a check, the first one remembers the htlc
+    def stuck_found():
+        bitcoind.generate_block(1)
+        return l1.daemon.is_in_log(r"with stuck htlcs: outgoing 100000 sats")
+
+    wait_for(stuck_found)
+    wait_for(
+        lambda: "stuck-htlc"
+        in [f["code"] for f in l1.rpc.call("vitality-findings")["findings"]]
+    )
+
+
+def test_htlc_force_close(node_factory, bitcoind, get_plugin):  # noqa: F811
+    os.environ["TEST_DEBUG"] = "true"
+    l1, l2 = stuck_htlc(
+        node_factory,
+        bitcoind,
+        {
+            "plugin": get_plugin,
+            "vitality-watch-channels": "true",
+            "vitality-htlc-force-close": 10,
+        },
+    )
+    channel = l1.rpc.listpeerchannels(l2.info["id"])["channels"][0]
+    scid = channel["short_channel_id"]
+    expiry = channel["htlcs"][0]["expiry"]
+
+    # block N: the htlc expires in 10 blocks, vitality warns and waits
+    bitcoind.generate_block(expiry - 10 - bitcoind.rpc.getblockcount())
+    sync_blockheight(bitcoind, [l1])
+    l1.daemon.wait_for_log(
+        r"Will force-close channel {} at the next block: .* expires in 10 blocks".format(
+            scid
+        )
+    )
+    time.sleep(5)
+    assert not l1.daemon.is_in_log(r"Force-closing channel")
+    assert (
+        l1.rpc.listpeerchannels(l2.info["id"])["channels"][0]["state"]
+        == "CHANNELD_NORMAL"
+    )
+
+    # block N+1: the htlc is still pending, vitality force-closes
+    bitcoind.generate_block(1)
+    l1.daemon.wait_for_log(
+        r"Force-closing channel {} to protect the upstream htlc".format(scid)
+    )
+    wait_for(
+        lambda: l1.rpc.listpeerchannels(l2.info["id"])["channels"][0]["state"]
+        == "AWAITING_UNILATERAL"
+    )